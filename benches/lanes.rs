@@ -0,0 +1,116 @@
+//! How the lanes the dispatcher hands outbox entries out by turn concurrency into speed.
+//!
+//! Run with `cargo bench --bench lanes`. Only [`Lanes`] is real here: there is no Signal
+//! service, outbox or worker, and sends are stubbed by sleeping. Most take a few
+//! milliseconds, while those to a few large groups take much longer, as fetching sessions
+//! and encrypting for every member would. The numbers tell how much waiting behind slow
+//! destinations the scheduling saves at each concurrency, not how fast the service sends.
+//! The cost of the scheduling itself is measured last, without any sends.
+
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+use tokio::task::{self, LocalSet};
+use tokio::time::sleep;
+
+#[path = "../src/lanes.rs"]
+#[allow(dead_code, unused_imports)]
+mod lanes;
+
+use lanes::Lanes;
+
+/// Messages sent per run, spread evenly over the destinations.
+const MESSAGES: usize = 400;
+const DESTINATIONS: usize = 40;
+/// Destinations standing for large groups, the first ones.
+const LARGE_GROUPS: usize = 4;
+
+const SEND_TIME: Duration = Duration::from_millis(5);
+const LARGE_GROUP_SEND_TIME: Duration = Duration::from_millis(50);
+
+/// Stubs sending every message with at most `concurrency` at once, and returns how long it
+/// took.
+///
+/// Panics if messages to a destination are not sent in the order they were queued.
+async fn run(concurrency: usize) -> Duration {
+    let mut lanes = Lanes::new(concurrency);
+    for id in 0..MESSAGES {
+        lanes.push(id % DESTINATIONS, id, Vec::new());
+    }
+    let (reports, mut finished) = mpsc::unbounded_channel();
+    let mut last_sent: Vec<Option<usize>> = vec![None; DESTINATIONS];
+
+    let start = Instant::now();
+    for _ in 0..MESSAGES {
+        while let Some((destination, id)) = lanes.next() {
+            let reports = reports.clone();
+            task::spawn_local(async move {
+                let send_time = if destination < LARGE_GROUPS {
+                    LARGE_GROUP_SEND_TIME
+                } else {
+                    SEND_TIME
+                };
+                sleep(send_time).await;
+                let _ = reports.send((destination, id));
+            });
+        }
+
+        let (destination, id) = finished.recv().await.expect("a send in progress");
+        assert!(
+            last_sent[destination] < Some(id),
+            "message {id} to {destination} sent out of order"
+        );
+        last_sent[destination] = Some(id);
+        lanes.done(&destination);
+    }
+
+    start.elapsed()
+}
+
+/// Messages pushed through the lanes to measure the cost of scheduling them.
+const SCHEDULED: usize = 1_000_000;
+
+/// Pushes [`SCHEDULED`] messages and hands each out and back in as soon as it can, with
+/// no sends in between.
+fn schedule() {
+    let mut lanes = Lanes::new(16);
+    for id in 0..SCHEDULED {
+        lanes.push(id % DESTINATIONS, id, Vec::new());
+    }
+    while let Some((destination, _)) = lanes.next() {
+        lanes.done(&destination);
+    }
+    assert_eq!(lanes.waiting(), 0, "messages left behind");
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .expect("failed to build runtime");
+
+    println!(
+        "{MESSAGES} messages to {DESTINATIONS} destinations, {LARGE_GROUPS} of them large groups"
+    );
+    println!("concurrency   time (ms)  messages/s  speedup");
+
+    let mut baseline = None;
+    for concurrency in [1, 2, 4, 8, 16, 32] {
+        let elapsed = LocalSet::new().block_on(&runtime, run(concurrency));
+        let baseline = *baseline.get_or_insert(elapsed);
+        println!(
+            "{concurrency:>11} {:>11} {:>11.0} {:>7.1}x",
+            elapsed.as_millis(),
+            MESSAGES as f64 / elapsed.as_secs_f64(),
+            baseline.as_secs_f64() / elapsed.as_secs_f64(),
+        );
+    }
+
+    let start = Instant::now();
+    schedule();
+    let elapsed = start.elapsed();
+    println!(
+        "scheduling alone: {:.0} ns per message",
+        elapsed.as_nanos() as f64 / SCHEDULED as f64
+    );
+}
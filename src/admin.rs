@@ -0,0 +1,241 @@
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::command::{Challenge, Command};
+use crate::outbox::{Entry, Outbox};
+use crate::problem::Problem;
+use crate::relayer::{dispatch, reserve};
+use crate::signal_service::Queue;
+
+/// Result of purging dead letters.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct Purged {
+    /// Number of dead letters deleted.
+    purged: usize,
+}
+
+/// Answer to the proof-of-humanity challenge Signal asked for.
+#[derive(Deserialize, ToSchema)]
+pub struct ChallengeAnswer {
+    /// Captcha token from <https://signalcaptchas.org/challenge/generate.html>, with or
+    /// without the `signalcaptcha://` prefix.
+    captcha: String,
+}
+
+fn missing(id: u64) -> Problem {
+    Problem::not_found("unknown-dead-letter", format!("no dead letter with id {id}"))
+}
+
+/// List messages that ran out of delivery attempts.
+#[utoipa::path(
+    get,
+    path = "/admin/dead-letters",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Dead letters, oldest first", body = [Entry]),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn list_dead_letters(
+    State(outbox): State<Outbox>,
+) -> Result<Json<Vec<Entry>>, Problem> {
+    outbox.dead_letters().map(Json).map_err(Problem::storage)
+}
+
+/// Inspect a dead-lettered message.
+#[utoipa::path(
+    get,
+    path = "/admin/dead-letters/{id}",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The dead letter", body = Entry),
+        (status = 404, description = "No dead letter with this id", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("id" = u64, Path, description = "Outbox id of the message")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn get_dead_letter(
+    Path(id): Path<u64>,
+    State(outbox): State<Outbox>,
+) -> Result<Json<Entry>, Problem> {
+    outbox
+        .dead_letter(id)
+        .map_err(Problem::storage)?
+        .map(Json)
+        .ok_or_else(|| missing(id))
+}
+
+/// Queue a dead-lettered message for delivery again, with a fresh set of attempts.
+#[utoipa::path(
+    post,
+    path = "/admin/dead-letters/{id}/replay",
+    tag = "admin",
+    responses(
+        (status = 202, description = "Message moved back to the outbox", body = Entry),
+        (status = 404, description = "No dead letter with this id", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("id" = u64, Path, description = "Outbox id of the message")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn replay_dead_letter(
+    Path(id): Path<u64>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+) -> Result<(StatusCode, Json<Entry>), Problem> {
+    let permit = reserve(&session)?;
+    let entry = outbox
+        .replay(id)
+        .map_err(Problem::storage)?
+        .ok_or_else(|| missing(id))?;
+
+    info!("replaying dead letter {id}");
+    match permit {
+        Some(permit) => permit.send(Command::Deliver(id)),
+        None => warn!("signal service is not running, message {id} stays queued"),
+    }
+
+    Ok((StatusCode::ACCEPTED, Json(entry)))
+}
+
+/// Delete a dead-lettered message.
+#[utoipa::path(
+    delete,
+    path = "/admin/dead-letters/{id}",
+    tag = "admin",
+    responses(
+        (status = 204, description = "Dead letter deleted"),
+        (status = 404, description = "No dead letter with this id", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("id" = u64, Path, description = "Outbox id of the message")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn purge_dead_letter(
+    Path(id): Path<u64>,
+    State(outbox): State<Outbox>,
+) -> Result<StatusCode, Problem> {
+    match outbox.purge(id).map_err(Problem::storage)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(missing(id)),
+    }
+}
+
+/// Delete all dead-lettered messages.
+#[utoipa::path(
+    delete,
+    path = "/admin/dead-letters",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Dead letters deleted", body = Purged),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn purge_dead_letters(State(outbox): State<Outbox>) -> Result<Json<Purged>, Problem> {
+    let purged = outbox.purge_all().map_err(Problem::storage)?;
+    info!("purged {purged} dead letters");
+
+    Ok(Json(Purged { purged }))
+}
+
+/// Inspect the proof-of-humanity challenge that paused sending.
+///
+/// Signal answers sends from an account it suspects of spamming with a challenge. Until
+/// it is answered, messages stay queued in the outbox.
+#[utoipa::path(
+    get,
+    path = "/admin/challenge",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The pending challenge", body = Challenge),
+        (status = 404, description = "No challenge is pending", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Signal service is not running", body = Problem, content_type = "application/problem+json")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn get_challenge(State(session): State<Queue>) -> Result<Json<Challenge>, Problem> {
+    dispatch(&session, Command::challenge())
+        .await?
+        .map(Json)
+        .ok_or_else(no_challenge)
+}
+
+/// Answer the proof-of-humanity challenge with a captcha and resume sending.
+#[utoipa::path(
+    post,
+    path = "/admin/challenge",
+    tag = "admin",
+    request_body = ChallengeAnswer,
+    responses(
+        (status = 204, description = "Challenge answered, sending resumed"),
+        (status = 400, description = "Malformed request body", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No challenge is pending", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Empty captcha", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Signal rejected the answer", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Signal could not be reached", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Signal service is not running", body = Problem, content_type = "application/problem+json")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn answer_challenge(
+    State(session): State<Queue>,
+    answer: Result<Json<ChallengeAnswer>, JsonRejection>,
+) -> Result<StatusCode, Problem> {
+    let Json(ChallengeAnswer { captcha }) = answer?;
+    let captcha = captcha.trim();
+    let captcha = captcha.strip_prefix("signalcaptcha://").unwrap_or(captcha);
+    if captcha.is_empty() {
+        return Err(Problem::unprocessable(
+            "invalid-captcha",
+            "captcha must not be empty",
+        ));
+    }
+
+    match dispatch(&session, Command::answer_challenge(captcha.to_owned())).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(no_challenge()),
+    }
+}
+
+fn no_challenge() -> Problem {
+    Problem::not_found("no-challenge", "sending is not paused by a challenge")
+}
@@ -8,6 +8,9 @@ use presage::{
 };
 
 use crate::logging::LoggingArguments;
+use crate::ratelimit::RateLimitArguments;
+use crate::receipts::ReceiptPolicy;
+use crate::retry::RetryArguments;
 
 #[derive(Parser)]
 #[clap(about = "A Rest API to Signal relayer")]
@@ -26,6 +29,12 @@ pub struct Args {
     )]
     pub passphrase: Option<String>,
 
+    #[clap(
+        help = "path of the outbound message queue, defaults to an `outbox` directory in the database path; it is not encrypted, even with a passphrase",
+        long = "outbox-path"
+    )]
+    pub outbox_path: Option<PathBuf>,
+
     #[clap(subcommand)]
     pub subcommand: Cmd,
 }
@@ -33,7 +42,50 @@ pub struct Args {
 #[derive(Subcommand)]
 pub enum Cmd {
     #[clap(about = "Start the relayer")]
-    Start,
+    Start {
+        #[clap(flatten)]
+        retry: RetryArguments,
+        #[clap(
+            long,
+            env,
+            value_enum,
+            default_value = "delivery",
+            help = "Receipts sent back automatically for incoming messages"
+        )]
+        receipts: ReceiptPolicy,
+        #[clap(
+            long,
+            env,
+            default_value = "86400",
+            help = "How long responses are kept for requests with an Idempotency-Key, in seconds"
+        )]
+        idempotency_window_secs: u64,
+        #[clap(
+            long,
+            env,
+            default_value = "604800",
+            help = "How long sent messages and dead letters are kept in the outbox, in seconds"
+        )]
+        outbox_retention_secs: u64,
+        #[clap(
+            long,
+            env,
+            default_value = "1000",
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Commands and messages waiting for the Signal service before requests are turned away"
+        )]
+        queue_capacity: u64,
+        #[clap(flatten)]
+        rate_limits: RateLimitArguments,
+        #[clap(
+            long,
+            env,
+            default_value = "8",
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Messages sent at once, to different contacts and groups; messages to the same one are sent in order"
+        )]
+        send_concurrency: u64,
+    },
     #[clap(about = "Register using a phone number")]
     Register {
         #[clap(long = "servers", short = 's', default_value = "staging")]
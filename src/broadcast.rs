@@ -0,0 +1,202 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::{multipart::MultipartRejection, rejection::JsonRejection, Multipart, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::destination::{parse_thread, Destination};
+use crate::outbox::{Outbox, Outgoing};
+use crate::problem::Problem;
+use crate::relayer::{accept, discard, queue_full, read_multipart, Accepted, Message};
+use crate::signal_service::Queue;
+
+/// Most recipients a single broadcast is sent to.
+pub const MAX_RECIPIENTS: usize = 100;
+
+/// A message to send to several recipients at once.
+#[derive(Deserialize, ToSchema)]
+pub struct BroadcastMessage {
+    /// Contact UUIDs or E.164 numbers, and group master keys or identifiers.
+    recipients: Vec<String>,
+    #[serde(flatten)]
+    message: Message,
+}
+
+/// A message with attachments to send to several recipients, as `multipart/form-data`.
+///
+/// The files are uploaded to Signal once and shared by every recipient.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct MultipartBroadcast {
+    /// A contact UUID or E.164 number, or a group master key or identifier, repeated once
+    /// per recipient.
+    recipient: Vec<String>,
+    /// Text sent along with the attachments.
+    caption: Option<String>,
+    /// HTTP(S) URL every change of the delivery status is POSTed to.
+    status_callback: Option<String>,
+    /// RFC 3339 time to send the message at.
+    #[schema(format = DateTime)]
+    send_at: Option<String>,
+    /// Seconds to wait before sending the message.
+    delay_seconds: Option<u64>,
+    /// The files to attach, repeated once per file.
+    #[schema(value_type = Vec<String>, format = Binary)]
+    file: Vec<Vec<u8>>,
+}
+
+/// What became of a broadcast for each recipient, in the order they were given.
+#[derive(Serialize, ToSchema)]
+pub struct Broadcast {
+    recipients: Vec<RecipientResult>,
+}
+
+/// What became of a broadcast for one recipient.
+#[derive(Serialize, ToSchema)]
+pub struct RecipientResult {
+    /// The recipient as given.
+    recipient: String,
+    /// The message queued for the recipient.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accepted: Option<Accepted>,
+    /// Why nothing was queued for the recipient.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Problem>,
+}
+
+fn check_recipients(session: &Queue, recipients: &[String]) -> Result<(), Problem> {
+    if recipients.is_empty() || recipients.len() > MAX_RECIPIENTS {
+        return Err(Problem::unprocessable(
+            "invalid-recipients",
+            format!("between 1 and {MAX_RECIPIENTS} recipients must be given"),
+        ));
+    }
+    // Turn the whole broadcast away rather than queue it for only some recipients.
+    if recipients.len() > session.capacity() {
+        return Err(queue_full(format!(
+            "{} places in the queue are left for {} recipients",
+            session.capacity(),
+            recipients.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Queues one outbox entry per recipient.
+///
+/// Entries share the stored message, so attachments are uploaded once, by whichever
+/// entry is sent first.
+async fn fan_out(
+    session: &Queue,
+    outbox: &Outbox,
+    recipients: Vec<String>,
+    message: Outgoing,
+) -> (StatusCode, Json<Broadcast>) {
+    let mut seen: HashSet<Destination> = HashSet::new();
+    let mut results = Vec::with_capacity(recipients.len());
+
+    for recipient in recipients {
+        let outcome = match parse_thread(&recipient) {
+            Err(e) => Err(Problem::bad_request("invalid-destination", e)),
+            Ok(destination) if !seen.insert(destination.clone()) => Err(Problem::unprocessable(
+                "duplicate-recipient",
+                format!("{recipient:?} was already given"),
+            )),
+            Ok(destination) => accept(session, outbox, destination, message.clone()).await,
+        };
+
+        let (accepted, error) = match outcome {
+            Ok(accepted) => (Some(accepted), None),
+            Err(problem) => (None, Some(problem)),
+        };
+        results.push(RecipientResult {
+            recipient,
+            accepted,
+            error,
+        });
+    }
+
+    (
+        StatusCode::ACCEPTED,
+        Json(Broadcast {
+            recipients: results,
+        }),
+    )
+}
+
+/// Send the same message to several contacts and groups.
+///
+/// Each recipient gets its own message, whose delivery can be followed through its id.
+/// Recipients that cannot be queued are reported with the problem, without affecting
+/// the others.
+#[utoipa::path(
+    post,
+    path = "/broadcast",
+    request_body = BroadcastMessage,
+    responses(
+        (status = 202, description = "Message queued for the recipients that were accepted", body = Broadcast),
+        (status = 400, description = "Malformed request body", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid message, or no or too many recipients", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue has no room for all recipients", body = Problem, content_type = "application/problem+json")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn broadcast(
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+    message: Result<Json<BroadcastMessage>, JsonRejection>,
+) -> Result<(StatusCode, Json<Broadcast>), Problem> {
+    let Json(BroadcastMessage {
+        recipients,
+        message,
+    }) = message?;
+    check_recipients(&session, &recipients)?;
+    let message = message.validate()?;
+
+    Ok(fan_out(&session, &outbox, recipients, message).await)
+}
+
+/// Send the same message with attachments to several contacts and groups.
+#[utoipa::path(
+    post,
+    path = "/broadcast/multipart",
+    request_body(content = MultipartBroadcast, content_type = "multipart/form-data"),
+    responses(
+        (status = 202, description = "Message queued for the recipients that were accepted", body = Broadcast),
+        (status = 400, description = "Malformed request body", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "No attachment, too many attachments, caption too long, unknown field, or no or too many recipients", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue has no room for all recipients", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Attachments could not be stored", body = Problem, content_type = "application/problem+json")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn broadcast_multipart(
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<(StatusCode, Json<Broadcast>), Problem> {
+    let mut recipients = Vec::new();
+    let message = read_multipart(&outbox, multipart?, Some(&mut recipients)).await?;
+    let attachments = message.attachments.clone();
+    check_recipients(&session, &recipients).map_err(|problem| {
+        discard(&outbox, &attachments);
+        problem
+    })?;
+
+    let (status, Json(broadcast)) = fan_out(&session, &outbox, recipients, message).await;
+    if broadcast.recipients.iter().all(|result| result.accepted.is_none()) {
+        // No entry refers to the attachments, so nothing would ever delete them.
+        discard(&outbox, &attachments);
+    }
+    Ok((status, Json(broadcast)))
+}
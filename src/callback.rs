@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use presage::prelude::Uuid;
+use serde::Serialize;
+use tracing::{debug, warn};
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::outbox::{DeliveryStatus, RecipientStatus};
+
+/// How long a status callback may take to answer.
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A change of the delivery status of a message, as POSTed to its status callback.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct StatusChange {
+    /// Outbox id of the message.
+    pub id: u64,
+    /// The timestamp the message was sent with, unless it never was.
+    pub timestamp: Option<u64>,
+    /// ACI of the recipient the status changed for.
+    #[schema(value_type = String)]
+    pub recipient: Uuid,
+    pub status: DeliveryStatus,
+    #[schema(value_type = String, format = DateTime)]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl StatusChange {
+    pub fn new(id: u64, timestamp: Option<u64>, recipient: Uuid, status: RecipientStatus) -> Self {
+        Self {
+            id,
+            timestamp,
+            recipient,
+            status: status.status,
+            updated_at: status.updated_at,
+        }
+    }
+}
+
+/// Reports status changes to the callback URLs given with messages.
+///
+/// Callbacks are best effort: each change is POSTed once, in the background, and
+/// failures are only logged.
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    client: reqwest::Client,
+}
+
+impl Callbacks {
+    pub fn notify(&self, url: &Url, change: StatusChange) {
+        let request = self
+            .client
+            .post(url.clone())
+            .timeout(CALLBACK_TIMEOUT)
+            .json(&change);
+        let url = url.clone();
+
+        tokio::spawn(async move {
+            match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => debug!(
+                    "reported {:?} of message {} to {url}",
+                    change.status, change.id
+                ),
+                Err(e) => warn!(
+                    "status callback to {url} for message {} failed: {e}",
+                    change.id
+                ),
+            }
+        });
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use presage::prelude::{MessageSenderError, ServiceError};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::debug;
+use utoipa::ToSchema;
+
+use crate::destination::Destination;
+
+/// Channel on which the service answers a single [`Command`].
+pub type Reply<T> = oneshot::Sender<Result<T, SendError>>;
+
+/// Receiving end of a [`Reply`].
+pub type Outcome<T> = oneshot::Receiver<Result<T, SendError>>;
+
+/// A request for the Signal service.
+pub enum Command {
+    /// Deliver the outbox entry with this id.
+    Deliver(u64),
+    /// Show or hide the typing indicator in a thread.
+    Typing {
+        destination: Destination,
+        started: bool,
+        /// Keep the indicator shown for this long, until stopped.
+        refresh_for: Option<Duration>,
+        reply: Reply<()>,
+    },
+    /// Tell the author of messages that they were read.
+    MarkRead {
+        author: Destination,
+        timestamps: Vec<u64>,
+        reply: Reply<()>,
+    },
+    /// Report the proof Signal asks for before sending more messages, if any.
+    Challenge { reply: Reply<Option<Challenge>> },
+    /// Answer the pending challenge with a captcha token and resume sending.
+    ///
+    /// Answers `false` if no challenge was pending.
+    AnswerChallenge { captcha: String, reply: Reply<bool> },
+}
+
+impl Command {
+    /// Creates a [`Command::Typing`] along with the receiver for its outcome.
+    pub fn typing(
+        destination: Destination,
+        started: bool,
+        refresh_for: Option<Duration>,
+    ) -> (Self, Outcome<()>) {
+        let (reply, outcome) = oneshot::channel();
+        let command = Self::Typing {
+            destination,
+            started,
+            refresh_for,
+            reply,
+        };
+        (command, outcome)
+    }
+
+    /// Creates a [`Command::MarkRead`] along with the receiver for its outcome.
+    pub fn mark_read(author: Destination, timestamps: Vec<u64>) -> (Self, Outcome<()>) {
+        let (reply, outcome) = oneshot::channel();
+        let command = Self::MarkRead {
+            author,
+            timestamps,
+            reply,
+        };
+        (command, outcome)
+    }
+
+    /// Creates a [`Command::Challenge`] along with the receiver for its outcome.
+    pub fn challenge() -> (Self, Outcome<Option<Challenge>>) {
+        let (reply, outcome) = oneshot::channel();
+        (Self::Challenge { reply }, outcome)
+    }
+
+    /// Creates a [`Command::AnswerChallenge`] along with the receiver for its outcome.
+    pub fn answer_challenge(captcha: String) -> (Self, Outcome<bool>) {
+        let (reply, outcome) = oneshot::channel();
+        (Self::AnswerChallenge { captcha, reply }, outcome)
+    }
+}
+
+/// Proof of humanity Signal asks for before the account may send more messages.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Challenge {
+    /// Token the answer to the challenge is submitted for.
+    pub token: String,
+    /// Kinds of proof Signal accepts, e.g. `recaptcha`.
+    pub options: Vec<String>,
+    /// When sending was paused.
+    #[schema(format = DateTime)]
+    pub since: DateTime<Utc>,
+    /// Outbox entries held until the challenge is answered.
+    pub held: usize,
+}
+
+/// Answers a command, ignoring callers that stopped waiting.
+pub fn respond<T>(reply: Reply<T>, result: Result<T, SendError>) {
+    if reply.send(result).is_err() {
+        debug!("caller went away before the command completed");
+    }
+}
+
+/// Why Signal did not accept a command.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum SendError {
+    /// The recipient is not registered with Signal.
+    UnknownRecipient,
+    /// Signal is throttling this account.
+    RateLimited,
+    /// Signal asks for proof of humanity before the account may send more messages.
+    ProofRequired { token: String, options: Vec<String> },
+    /// The recipient's safety number changed and has not been trusted yet.
+    UntrustedIdentity,
+    /// The Signal servers could not be reached.
+    Network(String),
+    /// Anything else, including local store failures.
+    Other(String),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownRecipient => write!(f, "recipient is not registered with Signal"),
+            Self::RateLimited => write!(f, "rate limited by the Signal servers"),
+            Self::ProofRequired { options, .. } => write!(
+                f,
+                "Signal requires proof of humanity ({}) before sending more messages",
+                options.join(", ")
+            ),
+            Self::UntrustedIdentity => write!(f, "recipient identity is not trusted"),
+            Self::Network(reason) => write!(f, "network failure: {reason}"),
+            Self::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl From<ServiceError> for SendError {
+    fn from(e: ServiceError) -> Self {
+        match e {
+            ServiceError::NotFoundError => Self::UnknownRecipient,
+            ServiceError::RateLimitExceeded => Self::RateLimited,
+            ServiceError::ProofRequiredError(proof) => Self::ProofRequired {
+                token: proof.token,
+                options: proof.options,
+            },
+            ServiceError::Timeout { .. }
+            | ServiceError::SendError { .. }
+            | ServiceError::WsError { .. }
+            | ServiceError::WsClosing { .. } => Self::Network(e.to_string()),
+            e => Self::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<MessageSenderError> for SendError {
+    fn from(e: MessageSenderError) -> Self {
+        match e {
+            MessageSenderError::NotFound { .. } => Self::UnknownRecipient,
+            MessageSenderError::UntrustedIdentity { .. } => Self::UntrustedIdentity,
+            MessageSenderError::ProofRequired { token, options } => {
+                Self::ProofRequired { token, options }
+            }
+            MessageSenderError::ServiceError(e) => e.into(),
+            e => Self::Other(e.to_string()),
+        }
+    }
+}
+
+impl<S: std::error::Error> From<presage::Error<S>> for SendError {
+    fn from(e: presage::Error<S>) -> Self {
+        match e {
+            presage::Error::MessageSenderError(e) => e.into(),
+            presage::Error::ServiceError(e) => e.into(),
+            e => Self::Other(e.to_string()),
+        }
+    }
+}
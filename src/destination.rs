@@ -0,0 +1,184 @@
+use std::fmt;
+use std::str::FromStr;
+
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use presage::prelude::phonenumber::{self, Mode, PhoneNumber};
+use presage::prelude::Uuid;
+use presage::Thread;
+use serde::{Deserialize, Serialize};
+
+/// Length of a group master key, and of a group identifier.
+pub const GROUP_KEY_LENGTH: usize = 32;
+
+/// Who a message is sent to.
+///
+/// Stored as a string: a bare UUID for contacts, an E.164 number for contacts known by
+/// phone number, `group:` followed by the base64 key for groups.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Destination {
+    Contact(Uuid),
+    /// A contact that still has to be resolved to its ACI.
+    PhoneNumber(PhoneNumber),
+    /// A group, by master key or group identifier.
+    Group([u8; GROUP_KEY_LENGTH]),
+}
+
+const GROUP_PREFIX: &str = "group:";
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Contact(uuid) => write!(f, "{uuid}"),
+            Self::PhoneNumber(number) => write!(f, "{}", number.format().mode(Mode::E164)),
+            Self::Group(key) => write!(f, "{GROUP_PREFIX}{}", STANDARD.encode(key)),
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(key) = s.strip_prefix(GROUP_PREFIX) {
+            return parse_group_key(key).map(Self::Group);
+        }
+        if s.starts_with('+') {
+            return parse_phone_number(s).map(Self::PhoneNumber);
+        }
+        Uuid::parse_str(s)
+            .map(Self::Contact)
+            .map_err(|e| format!("{s:?} is neither a UUID nor an E.164 number: {e}"))
+    }
+}
+
+impl From<&Thread> for Destination {
+    fn from(thread: &Thread) -> Self {
+        match thread {
+            Thread::Contact(uuid) => Self::Contact(*uuid),
+            Thread::Group(key) => Self::Group(*key),
+        }
+    }
+}
+
+impl TryFrom<String> for Destination {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Destination> for String {
+    fn from(destination: Destination) -> Self {
+        destination.to_string()
+    }
+}
+
+/// Parses a phone number in E.164 format, e.g. `+14155550123`.
+pub fn parse_phone_number(s: &str) -> Result<PhoneNumber, String> {
+    let digits = s
+        .strip_prefix('+')
+        .filter(|d| (2..=15).contains(&d.len()) && d.bytes().all(|b| b.is_ascii_digit()))
+        .ok_or_else(|| format!("{s:?} is not in E.164 format"))?;
+    if digits.starts_with('0') {
+        return Err(format!("{s:?} is not in E.164 format"));
+    }
+
+    let number = phonenumber::parse(None, s).map_err(|e| format!("{s:?}: {e}"))?;
+    if !phonenumber::is_valid(&number) {
+        return Err(format!("{s:?} is not a valid phone number"));
+    }
+    Ok(number)
+}
+
+/// Parses a group master key or identifier, given in base64 (standard or URL-safe, with
+/// or without padding) or hex.
+pub fn parse_group_key(s: &str) -> Result<[u8; GROUP_KEY_LENGTH], String> {
+    // A hex key is valid base64 as well, so keep the first decoding of the right length.
+    [STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD]
+        .iter()
+        .filter_map(|engine| engine.decode(s).ok())
+        .chain(hex::decode(s).ok())
+        .find_map(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| {
+            format!("{s:?} is not a {GROUP_KEY_LENGTH} byte group key in base64 or hex")
+        })
+}
+
+/// Parses the thread part of a path: a contact UUID or E.164 number, or a group master
+/// key or identifier.
+pub fn parse_thread(s: &str) -> Result<Destination, String> {
+    s.parse()
+        .or_else(|e| parse_group_key(s).map(Destination::Group).map_err(|_| e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; GROUP_KEY_LENGTH] = [0xfb; GROUP_KEY_LENGTH];
+    const UUID: &str = "6f7e2c1a-8a3d-4b55-9a43-3e0f0d7bbf4e";
+    const NUMBER: &str = "+41446681800";
+
+    #[test]
+    fn parses_destinations() {
+        let base64 = STANDARD.encode(KEY);
+        let url_safe = URL_SAFE_NO_PAD.encode(KEY);
+        let hex = hex::encode(KEY);
+        let contact = Destination::Contact(UUID.parse().unwrap());
+        let number = Destination::PhoneNumber(phonenumber::parse(None, NUMBER).unwrap());
+        let cases = [
+            (UUID.to_owned(), Some(contact)),
+            (NUMBER.to_owned(), Some(number)),
+            (format!("group:{base64}"), Some(Destination::Group(KEY))),
+            (format!("group:{url_safe}"), Some(Destination::Group(KEY))),
+            (format!("group:{hex}"), Some(Destination::Group(KEY))),
+            ("41446681800".to_owned(), None),
+            ("+041446681800".to_owned(), None),
+            ("+4144668180012345".to_owned(), None),
+            ("+4144abc".to_owned(), None),
+            (format!("group:{}", STANDARD.encode([0xfb; 16])), None),
+            (base64.clone(), None),
+            ("someone".to_owned(), None),
+        ];
+
+        for (s, expected) in cases {
+            assert_eq!(s.parse::<Destination>().ok(), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn parses_threads_with_bare_group_keys() {
+        let contact = Destination::Contact(UUID.parse().unwrap());
+        let cases = [
+            (STANDARD.encode(KEY), Some(Destination::Group(KEY))),
+            (hex::encode(KEY), Some(Destination::Group(KEY))),
+            (UUID.to_owned(), Some(contact)),
+            ("someone".to_owned(), None),
+        ];
+
+        for (s, expected) in cases {
+            assert_eq!(parse_thread(&s).ok(), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let destinations = [
+            Destination::Contact(UUID.parse().unwrap()),
+            Destination::PhoneNumber(parse_phone_number(NUMBER).unwrap()),
+            Destination::Group(KEY),
+        ];
+
+        for destination in destinations {
+            let s = destination.to_string();
+            assert_eq!(s.parse::<Destination>(), Ok(destination), "{s}");
+        }
+        assert_eq!(
+            Destination::Group(KEY).to_string(),
+            format!("group:{}", STANDARD.encode(KEY))
+        );
+    }
+}
@@ -0,0 +1,442 @@
+use std::time::Duration;
+
+use axum::{
+    body::{self, Body, Full, HttpBody},
+    extract::State,
+    http::{header, HeaderValue, Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::time::interval;
+use tracing::{debug, error, warn};
+
+use crate::outbox::Outbox;
+use crate::problem::Problem;
+use crate::relayer::MAX_MULTIPART_SIZE;
+
+/// Header clients set to make a request safe to retry.
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Header set on responses replayed for a repeated request.
+const REPLAYED: &str = "idempotent-replayed";
+
+/// Longest idempotency key accepted, in bytes.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// How long a key is held for a request that never completed, e.g. across a crash.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often expired keys are dropped.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What is known about a request with a given key.
+///
+/// Each record keeps the SHA-256 hash of the request body, hex encoded, so a key reused
+/// for a different request is told apart from a retry.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum Record {
+    /// Still being handled.
+    Claimed {
+        at: DateTime<Utc>,
+        #[serde(default)]
+        fingerprint: Option<String>,
+    },
+    /// Handled, with the response to replay.
+    Completed {
+        at: DateTime<Utc>,
+        #[serde(default)]
+        fingerprint: Option<String>,
+        status: u16,
+        content_type: Option<String>,
+        body: Vec<u8>,
+    },
+}
+
+impl Record {
+    /// Whether the record was left by a request with a different body.
+    fn conflicts_with(&self, fingerprint: &str) -> bool {
+        let kept = match self {
+            Self::Claimed { fingerprint, .. } | Self::Completed { fingerprint, .. } => fingerprint,
+        };
+        // Records kept before bodies were hashed match any body.
+        kept.as_deref().is_some_and(|kept| kept != fingerprint)
+    }
+}
+
+/// Responses to mutating requests, by idempotency key.
+///
+/// Keys are scoped to the method and path of the request, and kept for a configurable
+/// window. Responses to requests that may succeed when retried, server errors and
+/// `429 Too Many Requests`, are not kept.
+#[derive(Clone)]
+pub struct IdempotencyKeys {
+    tree: sled::Tree,
+    window: Duration,
+}
+
+impl IdempotencyKeys {
+    pub fn open(outbox: &Outbox, window: Duration) -> anyhow::Result<Self> {
+        Ok(Self {
+            tree: outbox.open_tree("idempotency_keys")?,
+            window,
+        })
+    }
+
+    fn expired(&self, record: &Record) -> bool {
+        let (at, ttl) = match record {
+            Record::Claimed { at, .. } => (at, CLAIM_TIMEOUT),
+            Record::Completed { at, .. } => (at, self.window),
+        };
+        (Utc::now() - *at).to_std().is_ok_and(|age| age > ttl)
+    }
+
+    /// Claims `key` for a new request, or returns what an earlier request left with it.
+    fn claim(&self, key: &[u8], fingerprint: &str) -> anyhow::Result<Option<Record>> {
+        let claim = serde_json::to_vec(&Record::Claimed {
+            at: Utc::now(),
+            fingerprint: Some(fingerprint.to_owned()),
+        })?;
+        loop {
+            let current = self.tree.get(key)?;
+            if let Some(value) = &current {
+                let record: Record = serde_json::from_slice(value)?;
+                if !self.expired(&record) {
+                    return Ok(Some(record));
+                }
+            }
+            if self
+                .tree
+                .compare_and_swap(key, current, Some(claim.as_slice()))?
+                .is_ok()
+            {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn complete(
+        &self,
+        key: &[u8],
+        fingerprint: &str,
+        status: StatusCode,
+        content_type: Option<&HeaderValue>,
+        body: &[u8],
+    ) -> anyhow::Result<()> {
+        let record = Record::Completed {
+            at: Utc::now(),
+            fingerprint: Some(fingerprint.to_owned()),
+            status: status.as_u16(),
+            content_type: content_type
+                .and_then(|c| c.to_str().ok())
+                .map(str::to_owned),
+            body: body.to_vec(),
+        };
+        self.tree.insert(key, serde_json::to_vec(&record)?)?;
+        Ok(())
+    }
+
+    fn release(&self, key: &[u8]) {
+        if let Err(e) = self.tree.remove(key) {
+            error!("failed to release idempotency key: {e}");
+        }
+    }
+
+    /// Drops expired keys every [`PURGE_INTERVAL`], forever.
+    pub async fn purge_expired(self) {
+        let mut ticks = interval(PURGE_INTERVAL);
+        loop {
+            ticks.tick().await;
+
+            let mut purged = 0;
+            for item in self.tree.iter() {
+                let Ok((key, value)) = item else { continue };
+                let expired = match serde_json::from_slice(&value) {
+                    Ok(record) => self.expired(&record),
+                    Err(_) => true,
+                };
+                if !expired {
+                    continue;
+                }
+                // Skip keys claimed again since they were read.
+                let removed = self.tree.compare_and_swap(key, Some(value), None::<&[u8]>);
+                if matches!(removed, Ok(Ok(()))) {
+                    purged += 1;
+                }
+            }
+            debug!("purged {purged} expired idempotency keys");
+        }
+    }
+}
+
+/// Answers repeated mutating requests carrying an `Idempotency-Key` header with the
+/// response to the first one, without handling them again.
+///
+/// A repeated request arriving while the first is still handled gets `409 Conflict`, and
+/// one with a different body than the first gets `422 Unprocessable Entity`.
+pub async fn idempotent(
+    State(keys): State<IdempotencyKeys>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let mutating = matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    );
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY).filter(|_| mutating) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if (1..=MAX_KEY_LENGTH).contains(&key.len()) => key,
+        _ => {
+            return Problem::bad_request(
+                "invalid-idempotency-key",
+                format!("idempotency key must be 1 to {MAX_KEY_LENGTH} visible ASCII characters"),
+            )
+            .into_response()
+        }
+    };
+    let key = format!("{} {} {key}", request.method(), request.uri().path());
+
+    let (parts, body) = request.into_parts();
+    let (body, fingerprint) = match fingerprint(body).await {
+        Ok(read) => read,
+        Err(problem) => return problem.into_response(),
+    };
+    let request = Request::from_parts(parts, Body::from(body));
+
+    match keys.claim(key.as_bytes(), &fingerprint) {
+        Ok(None) => {}
+        Ok(Some(record)) if record.conflicts_with(&fingerprint) => {
+            return Problem::unprocessable(
+                "idempotency-key-reused",
+                "the idempotency key was used for a request with a different body",
+            )
+            .into_response()
+        }
+        Ok(Some(Record::Claimed { .. })) => {
+            return Problem::new(
+                StatusCode::CONFLICT,
+                "request-in-progress",
+                "Request in progress",
+            )
+            .with_detail("a request with this idempotency key is still being handled")
+            .into_response()
+        }
+        Ok(Some(Record::Completed {
+            status,
+            content_type,
+            body,
+            ..
+        })) => {
+            debug!("replaying response for {key}");
+            return replay(status, content_type, body);
+        }
+        Err(e) => {
+            return Problem::internal(format!("idempotency key storage failure: {e}"))
+                .into_response()
+        }
+    }
+
+    let response = next.run(request).await;
+    let status = response.status();
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        keys.release(key.as_bytes());
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            keys.release(key.as_bytes());
+            return Problem::internal(format!("failed to read response: {e}")).into_response();
+        }
+    };
+    if let Err(e) = keys.complete(
+        key.as_bytes(),
+        &fingerprint,
+        status,
+        parts.headers.get(header::CONTENT_TYPE),
+        &body,
+    ) {
+        warn!("failed to store response for {key}: {e}");
+        keys.release(key.as_bytes());
+    }
+
+    Response::from_parts(parts, body::boxed(Full::from(body)))
+}
+
+/// Reads the body of a request, returning it along with its fingerprint.
+///
+/// Bodies larger than any request the API takes are turned away rather than buffered.
+async fn fingerprint(mut body: Body) -> Result<(Vec<u8>, String), Problem> {
+    let mut read = Vec::new();
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            Problem::bad_request("invalid-body", format!("failed to read request body: {e}"))
+        })?;
+        if read.len() + chunk.len() > MAX_MULTIPART_SIZE {
+            return Err(Problem::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "body-too-large",
+                "Payload too large",
+            )
+            .with_detail(format!("request body exceeds {MAX_MULTIPART_SIZE} bytes")));
+        }
+        hasher.update(&chunk);
+        read.extend_from_slice(&chunk);
+    }
+
+    Ok((read, hex::encode(hasher.finalize())))
+}
+
+fn replay(status: u16, content_type: Option<String>, body: Vec<u8>) -> Response {
+    let mut response = Response::builder().status(status).header(REPLAYED, "true");
+    if let Some(content_type) = content_type {
+        response = response.header(header::CONTENT_TYPE, content_type);
+    }
+
+    response
+        .body(body::boxed(Full::from(body)))
+        .unwrap_or_else(|e| Problem::internal(e.to_string()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::{extract::Path, middleware, routing::post, Router};
+    use tower::Service;
+
+    use super::*;
+
+    struct Harness {
+        _dir: tempfile::TempDir,
+        keys: IdempotencyKeys,
+        app: Router,
+        handled: Arc<AtomicUsize>,
+    }
+
+    /// Serves `POST /{status}`, answering with that status and counting the requests
+    /// that got through.
+    fn harness(window: Duration) -> Harness {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path()).unwrap();
+        let keys = IdempotencyKeys::open(&outbox, window).unwrap();
+        let handled = Arc::new(AtomicUsize::new(0));
+
+        let counter = handled.clone();
+        let app = Router::new()
+            .route(
+                "/:status",
+                post(move |Path(status): Path<u16>| async move {
+                    let id = counter.fetch_add(1, Ordering::SeqCst);
+                    let status = StatusCode::from_u16(status).unwrap();
+                    (
+                        status,
+                        [(header::CONTENT_TYPE, "application/json")],
+                        format!("{{\"id\":{id}}}"),
+                    )
+                }),
+            )
+            .route_layer(middleware::from_fn_with_state(keys.clone(), idempotent));
+
+        Harness {
+            _dir: dir,
+            keys,
+            app,
+            handled,
+        }
+    }
+
+    impl Harness {
+        async fn send(&mut self, path: &str, body: &'static str) -> (StatusCode, Response) {
+            let request = Request::post(path)
+                .header(IDEMPOTENCY_KEY, "key-1")
+                .body(Body::from(body))
+                .unwrap();
+            let response = self.app.call(request).await.unwrap();
+            (response.status(), response)
+        }
+
+        fn handled(&self) -> usize {
+            self.handled.load(Ordering::SeqCst)
+        }
+    }
+
+    async fn body(response: Response) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replays_the_stored_response() {
+        let mut harness = harness(Duration::from_secs(60));
+
+        let (status, first) = harness.send("/201", "{}").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(first.headers().get(REPLAYED).is_none());
+        assert_eq!(body(first).await, r#"{"id":0}"#);
+
+        let (status, replayed) = harness.send("/201", "{}").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(replayed.headers()[REPLAYED], "true");
+        assert_eq!(replayed.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body(replayed).await, r#"{"id":0}"#);
+        assert_eq!(harness.handled(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_while_one_with_the_same_key_is_handled() {
+        let mut harness = harness(Duration::from_secs(60));
+        let (_, fingerprint) = fingerprint(Body::from("{}")).await.unwrap();
+        let claimed = harness
+            .keys
+            .claim(b"POST /201 key-1", &fingerprint)
+            .unwrap();
+        assert!(claimed.is_none());
+
+        let (status, _) = harness.send("/201", "{}").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(harness.handled(), 0);
+    }
+
+    #[tokio::test]
+    async fn releases_the_key_when_a_retry_may_succeed() {
+        let mut harness = harness(Duration::from_secs(60));
+
+        for path in ["/503", "/503", "/429", "/201"] {
+            harness.send(path, "{}").await;
+        }
+        assert_eq!(harness.handled(), 4);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_key_reused_with_a_different_body() {
+        let mut harness = harness(Duration::from_secs(60));
+
+        harness.send("/201", r#"{"content":"a"}"#).await;
+        let (status, _) = harness.send("/201", r#"{"content":"b"}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(harness.handled(), 1);
+    }
+
+    #[tokio::test]
+    async fn forgets_keys_after_the_window() {
+        let mut harness = harness(Duration::from_millis(1));
+
+        harness.send("/201", "{}").await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (status, response) = harness.send("/201", "{}").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(response.headers().get(REPLAYED).is_none());
+        assert_eq!(harness.handled(), 2);
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::iter;
+
+/// Items waiting for one key, and whether one is being worked on.
+struct Lane<K, T> {
+    busy: bool,
+    /// Whether the lane hands out nothing until it is [resumed](Lanes::resume).
+    parked: bool,
+    /// Items waiting, each with the other keys it holds while it is worked on.
+    waiting: VecDeque<(T, Vec<K>)>,
+    /// The other keys held by the item being worked on.
+    holding: Vec<K>,
+}
+
+/// Work split in lanes by key: items with the same key are handed out one at a time, in
+/// the order they were pushed, while items with different keys are handed out side by
+/// side, up to a limit.
+///
+/// Keys take turns: once its item is done, a key with more waiting goes behind the keys
+/// that were already waiting, so one busy key does not hold up the others.
+///
+/// An item may hold other keys besides its own while it is worked on, and is not handed
+/// out while any of them is held. It keeps its turn meanwhile: items needing any of the
+/// same keys wait behind it.
+pub struct Lanes<K, T> {
+    limit: usize,
+    busy: usize,
+    lanes: HashMap<K, Lane<K, T>>,
+    /// Keys with items waiting and none being worked on, in the order they became ready.
+    ready: VecDeque<K>,
+    /// Keys held by the items being worked on, with how many items hold each.
+    held: HashMap<K, usize>,
+}
+
+impl<K: Hash + Eq + Clone, T> Lanes<K, T> {
+    /// Creates lanes handing out at most `limit` items at once, and at least one.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            busy: 0,
+            lanes: HashMap::new(),
+            ready: VecDeque::new(),
+            held: HashMap::new(),
+        }
+    }
+
+    /// Adds an item behind those already waiting with the same key, holding the keys in
+    /// `also` too while it is worked on.
+    pub fn push(&mut self, key: K, item: T, also: Vec<K>) {
+        let lane = self.lanes.entry(key.clone()).or_insert_with(|| Lane {
+            busy: false,
+            parked: false,
+            waiting: VecDeque::new(),
+            holding: Vec::new(),
+        });
+        lane.waiting.push_back((item, also));
+        if !lane.busy && !lane.parked && lane.waiting.len() == 1 {
+            self.ready.push_back(key);
+        }
+    }
+
+    /// Hands out the next item to work on, if the limit and the keys held allow for one.
+    ///
+    /// The key stays busy until the item is [done](Self::done),
+    /// [parked](Self::park) or [given back](Self::give_back).
+    pub fn next(&mut self) -> Option<(K, T)> {
+        if self.busy >= self.limit {
+            return None;
+        }
+
+        // Keys of the items skipped so far, which later items must not overtake.
+        let mut wanted = HashSet::new();
+        let index = self.ready.iter().position(|key| {
+            let Some((_, also)) = self.lanes.get(key).and_then(|lane| lane.waiting.front()) else {
+                return false;
+            };
+            let free = iter::once(key)
+                .chain(also)
+                .all(|key| !self.held.contains_key(key) && !wanted.contains(key));
+            if !free {
+                wanted.extend(iter::once(key).chain(also));
+            }
+            free
+        })?;
+
+        let key = self.ready.remove(index)?;
+        let lane = self.lanes.get_mut(&key)?;
+        let (item, also) = lane.waiting.pop_front()?;
+        for held in iter::once(&key).chain(&also) {
+            *self.held.entry(held.clone()).or_default() += 1;
+        }
+        lane.busy = true;
+        lane.holding = also;
+        self.busy += 1;
+
+        Some((key, item))
+    }
+
+    /// Marks the item handed out for `key` as done, letting the next one for it go.
+    pub fn done(&mut self, key: &K) {
+        let Some(lane) = self.release(key) else {
+            return;
+        };
+
+        if lane.waiting.is_empty() {
+            self.lanes.remove(key);
+        } else if !lane.parked {
+            self.ready.push_back(key.clone());
+        }
+    }
+
+    /// Puts back an item handed out for `key`, ahead of the others waiting for it, and
+    /// hands out nothing more for `key` until it is [resumed](Self::resume).
+    pub fn park(&mut self, key: K, item: T) {
+        let also = match self.release(&key) {
+            Some(lane) => std::mem::take(&mut lane.holding),
+            None => {
+                self.ready.retain(|ready| *ready != key);
+                Vec::new()
+            }
+        };
+        let lane = self.lanes.entry(key).or_insert_with(|| Lane {
+            busy: false,
+            parked: false,
+            waiting: VecDeque::new(),
+            holding: Vec::new(),
+        });
+        lane.waiting.push_front((item, also));
+        lane.parked = true;
+    }
+
+    /// Lets a [parked](Self::park) key hand out items again, starting with the one put
+    /// back.
+    pub fn resume(&mut self, key: &K) {
+        let Some(lane) = self.lanes.get_mut(key).filter(|lane| lane.parked) else {
+            return;
+        };
+        lane.parked = false;
+        if !lane.busy && !lane.waiting.is_empty() {
+            self.ready.push_back(key.clone());
+        }
+    }
+
+    /// Puts back an item handed out for `key`, ahead of the others waiting for it.
+    pub fn give_back(&mut self, key: K, item: T) {
+        self.park(key.clone(), item);
+        self.resume(&key);
+    }
+
+    /// The item put back for `key`, if it waits to be [resumed](Self::resume).
+    pub fn parked(&self, key: &K) -> Option<&T> {
+        let lane = self.lanes.get(key).filter(|lane| lane.parked)?;
+        lane.waiting.front().map(|(item, _)| item)
+    }
+
+    /// Number of items handed out and not done yet.
+    pub fn busy(&self) -> usize {
+        self.busy
+    }
+
+    /// Number of items waiting to be handed out.
+    pub fn waiting(&self) -> usize {
+        self.lanes.values().map(|lane| lane.waiting.len()).sum()
+    }
+
+    /// Ends the work on the item handed out for `key`, releasing the keys it held.
+    fn release(&mut self, key: &K) -> Option<&mut Lane<K, T>> {
+        let lane = self.lanes.get_mut(key).filter(|lane| lane.busy)?;
+        lane.busy = false;
+        self.busy -= 1;
+
+        for held in iter::once(key).chain(&lane.holding) {
+            if let Some(count) = self.held.get_mut(held) {
+                *count -= 1;
+                if *count == 0 {
+                    self.held.remove(held);
+                }
+            }
+        }
+        Some(lane)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_items_of_a_key_one_at_a_time_in_order() {
+        let mut lanes = Lanes::new(8);
+        lanes.push("a", 1, Vec::new());
+        lanes.push("a", 2, Vec::new());
+        lanes.push("b", 3, Vec::new());
+
+        assert_eq!(lanes.next(), Some(("a", 1)));
+        assert_eq!(lanes.next(), Some(("b", 3)));
+        assert_eq!(lanes.next(), None);
+        assert_eq!((lanes.busy(), lanes.waiting()), (2, 1));
+
+        lanes.done(&"a");
+        assert_eq!(lanes.next(), Some(("a", 2)));
+        lanes.done(&"a");
+        lanes.done(&"b");
+        assert_eq!(lanes.next(), None);
+        assert_eq!((lanes.busy(), lanes.waiting()), (0, 0));
+    }
+
+    #[test]
+    fn hands_out_no_more_than_the_limit() {
+        let mut lanes = Lanes::new(2);
+        for (key, item) in [("a", 1), ("b", 2), ("c", 3)] {
+            lanes.push(key, item, Vec::new());
+        }
+
+        assert_eq!(lanes.next(), Some(("a", 1)));
+        assert_eq!(lanes.next(), Some(("b", 2)));
+        assert_eq!(lanes.next(), None);
+
+        lanes.done(&"b");
+        assert_eq!(lanes.next(), Some(("c", 3)));
+        assert_eq!(lanes.busy(), 2);
+    }
+
+    #[test]
+    fn keys_take_turns() {
+        let mut lanes = Lanes::new(1);
+        lanes.push("a", 1, Vec::new());
+        lanes.push("a", 2, Vec::new());
+        lanes.push("b", 3, Vec::new());
+
+        assert_eq!(lanes.next(), Some(("a", 1)));
+        lanes.done(&"a");
+        assert_eq!(lanes.next(), Some(("b", 3)));
+        lanes.done(&"b");
+        assert_eq!(lanes.next(), Some(("a", 2)));
+    }
+
+    #[test]
+    fn items_wait_for_the_other_keys_they_hold() {
+        let mut lanes = Lanes::new(8);
+        lanes.push("a", 1, Vec::new());
+        assert_eq!(lanes.next(), Some(("a", 1)));
+
+        lanes.push("group", 2, vec!["a", "b"]);
+        lanes.push("c", 3, Vec::new());
+        assert_eq!(lanes.next(), Some(("c", 3)));
+        assert_eq!(lanes.next(), None);
+
+        lanes.done(&"a");
+        assert_eq!(lanes.next(), Some(("group", 2)));
+
+        // Held by the group now.
+        lanes.push("b", 4, Vec::new());
+        assert_eq!(lanes.next(), None);
+        lanes.done(&"group");
+        assert_eq!(lanes.next(), Some(("b", 4)));
+    }
+
+    #[test]
+    fn items_do_not_overtake_those_waiting_for_the_same_keys() {
+        let mut lanes = Lanes::new(8);
+        lanes.push("a", 1, Vec::new());
+        assert_eq!(lanes.next(), Some(("a", 1)));
+
+        lanes.push("group", 2, vec!["a", "b"]);
+        lanes.push("b", 3, Vec::new());
+        assert_eq!(lanes.next(), None);
+
+        lanes.done(&"a");
+        assert_eq!(lanes.next(), Some(("group", 2)));
+        lanes.done(&"group");
+        assert_eq!(lanes.next(), Some(("b", 3)));
+    }
+
+    #[test]
+    fn parked_items_stay_first_until_resumed() {
+        let mut lanes = Lanes::new(8);
+        lanes.push("a", 1, vec!["b"]);
+        lanes.push("a", 2, Vec::new());
+        assert_eq!(lanes.next(), Some(("a", 1)));
+
+        // Retried later: neither it nor the items behind it are handed out meanwhile.
+        lanes.park("a", 1);
+        assert_eq!(lanes.parked(&"a"), Some(&1));
+        lanes.push("a", 3, Vec::new());
+        assert_eq!(lanes.next(), None);
+        assert_eq!((lanes.busy(), lanes.waiting()), (0, 3));
+
+        // The keys it holds are free while it waits.
+        lanes.push("b", 4, Vec::new());
+        assert_eq!(lanes.next(), Some(("b", 4)));
+        lanes.done(&"b");
+
+        lanes.resume(&"a");
+        assert_eq!(lanes.parked(&"a"), None);
+        for item in [1, 2, 3] {
+            assert_eq!(lanes.next(), Some(("a", item)));
+            lanes.done(&"a");
+        }
+        assert_eq!(lanes.next(), None);
+    }
+
+    #[test]
+    fn items_given_back_go_first() {
+        let mut lanes = Lanes::new(8);
+        lanes.push("a", 1, Vec::new());
+        lanes.push("a", 2, Vec::new());
+        assert_eq!(lanes.next(), Some(("a", 1)));
+
+        lanes.give_back("a", 1);
+        assert_eq!(lanes.parked(&"a"), None);
+        assert_eq!(lanes.next(), Some(("a", 1)));
+        lanes.done(&"a");
+        assert_eq!(lanes.next(), Some(("a", 2)));
+    }
+}
@@ -1,18 +1,40 @@
 use arguments::Cmd;
+use idempotency::IdempotencyKeys;
+use outbox::Outbox;
+use resolver::{CachingResolver, ContactStoreResolver};
+use std::path::PathBuf;
+use std::time::Duration;
 use clap::Parser;
 use directories::ProjectDirs;
 use presage::{Manager, RegistrationOptions, Store};
 use presage_store_sled::{SledStore, MigrationConflictStrategy};
 use crate::arguments::Args;
-use signal_service::SignalServiceWrapper;
-use tokio::sync::mpsc;
+use signal_service::{Policies, SignalServiceWrapper};
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 
+pub mod admin;
+pub mod callback;
 pub mod arguments;
+pub mod broadcast;
+pub mod command;
+pub mod destination;
+pub mod idempotency;
+pub mod lanes;
 pub mod service;
 pub mod relayer;
+pub mod resolver;
 pub mod signal_service;
+pub mod text;
+pub mod threads;
 pub mod logging;
+pub mod messages;
+pub mod outbox;
+pub mod problem;
+pub mod ratelimit;
+pub mod receipts;
+pub mod retry;
+pub mod schedule;
+pub mod scheduling_api;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -29,16 +51,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .config_dir()
             .into()
     });
+    if args.passphrase.is_some() {
+        tracing::warn!(
+            "the passphrase only encrypts the Signal store, the outbox is stored in plain text"
+        );
+    }
     let config_store = SledStore::open_with_passphrase(
         db_path.clone(),
         args.passphrase,
         MigrationConflictStrategy::Raise,
     ).expect("failed to open config database");
+    let outbox_path = args.outbox_path.unwrap_or_else(|| db_path.join("outbox"));
 
-    run(args.subcommand, config_store).await
+    run(args.subcommand, config_store, outbox_path).await
 }
 
-async fn run<C: Store + 'static>(subcommand: Cmd, config_store: C) -> Result<(), Box<dyn std::error::Error>> {
+async fn run<C: Store + 'static>(
+    subcommand: Cmd,
+    config_store: C,
+    outbox_path: PathBuf,
+) -> Result<(), Box<dyn std::error::Error>> {
     match subcommand {
         Cmd::Register {
             servers,
@@ -68,14 +100,46 @@ async fn run<C: Store + 'static>(subcommand: Cmd, config_store: C) -> Result<(),
                 return Err("Failed to read confirmation code from stdin".into());
             }
         },
-        Cmd::Start => {
+        Cmd::Start {
+            retry,
+            receipts,
+            idempotency_window_secs,
+            outbox_retention_secs,
+            queue_capacity,
+            rate_limits,
+            send_concurrency,
+        } => {
+            let outbox = Outbox::open(&outbox_path)?;
+            tokio::task::spawn(
+                outbox
+                    .clone()
+                    .prune_expired(Duration::from_secs(outbox_retention_secs)),
+            );
+
             // Create the channel
-            let (tx, rx) = mpsc::unbounded_channel::<(String, String)>();
+            let (tx, rx) = signal_service::queue(queue_capacity as usize);
         
-            tokio::task::spawn(service::start(tx));
+            let idempotency_keys = IdempotencyKeys::open(
+                &outbox,
+                Duration::from_secs(idempotency_window_secs),
+            )?;
+            tokio::task::spawn(service::start(tx, outbox.clone(), idempotency_keys));
         
-            let signal_service = SignalServiceWrapper::new(rx, config_store.clone());
-            signal_service.run().await;
+            let resolver = CachingResolver::new(ContactStoreResolver::new(config_store.clone(), None));
+            let policies = Policies {
+                retry: retry.into(),
+                receipts,
+                rate_limiter: rate_limits.into(),
+            };
+            let signal_service = SignalServiceWrapper::new(
+                rx,
+                config_store.clone(),
+                outbox,
+                policies,
+                Box::new(resolver),
+                send_concurrency as usize,
+            );
+            signal_service.run().await?;
         }
     }
     
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use presage::prelude::Uuid;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::command::SendError;
+use crate::destination::Destination;
+use crate::outbox::{Outbox, RecipientStatus, State as EntryState};
+use crate::problem::Problem;
+
+/// Where a message is in its delivery.
+#[derive(Serialize, ToSchema)]
+pub struct MessageStatus {
+    /// Outbox id of the message.
+    id: u64,
+    /// The timestamp the message is sent with, which identifies it in the thread. Assigned
+    /// on the first attempt to send the message, and needed to edit or delete it.
+    timestamp: Option<u64>,
+    #[schema(value_type = String)]
+    destination: Destination,
+    /// Where the message is in the outbox.
+    state: EntryState,
+    attempts: u32,
+    last_error: Option<SendError>,
+    /// Delivery status per recipient ACI, once the recipients are known.
+    recipients: BTreeMap<Uuid, RecipientStatus>,
+}
+
+/// Get the delivery status of a message.
+#[utoipa::path(
+    get,
+    path = "/messages/{id}",
+    responses(
+        (status = 200, description = "The delivery status of the message", body = MessageStatus),
+        (status = 404, description = "No message with this id", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("id" = u64, Path, description = "Outbox id of the message")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn get_message(
+    Path(id): Path<u64>,
+    State(outbox): State<Outbox>,
+) -> Result<Json<MessageStatus>, Problem> {
+    let entry = outbox
+        .find(id)
+        .map_err(Problem::storage)?
+        .ok_or_else(|| Problem::not_found("unknown-message", format!("no message with id {id}")))?;
+    let recipients = outbox.delivery(id).map_err(Problem::storage)?;
+
+    Ok(Json(MessageStatus {
+        id: entry.id,
+        timestamp: entry.timestamp,
+        destination: entry.destination,
+        state: entry.state,
+        attempts: entry.attempts,
+        last_error: entry.last_error,
+        recipients,
+    }))
+}
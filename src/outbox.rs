@@ -0,0 +1,1152 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use presage::prelude::Uuid;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, Transactional};
+use tokio::time::interval;
+use tracing::{error, info};
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::command::SendError;
+use crate::destination::Destination;
+use crate::retry::RetryPolicy;
+
+/// How often sent messages and dead letters past their retention are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Where an [`Entry`] is in its delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Accepted and waiting to be sent.
+    Pending,
+    /// Handed to Signal, outcome not recorded yet.
+    InFlight,
+    /// Accepted by Signal.
+    Sent,
+    /// Rejected by Signal for good, see the dead letters.
+    Failed,
+}
+
+/// What is sent, independent of who it is sent to.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct Outgoing {
+    #[serde(default)]
+    pub body: Option<String>,
+    /// Ids of the stored attachments, in order.
+    #[serde(default)]
+    pub attachments: Vec<u64>,
+    #[serde(default)]
+    pub quote: Option<QuoteTarget>,
+    #[serde(default)]
+    pub reaction: Option<ReactionTarget>,
+    /// The timestamp of an earlier message to delete for everyone.
+    #[serde(default)]
+    pub delete: Option<u64>,
+    /// The timestamp of the original message this one replaces.
+    ///
+    /// Edits always target the original, see [`Outbox::push`].
+    #[serde(default)]
+    pub edit: Option<u64>,
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+    #[serde(default)]
+    pub styles: Vec<StyleRange>,
+    /// Seconds after which the message disappears once read, overriding the thread's timer.
+    ///
+    /// Clients take it as the timer of a 1:1 chat from then on, so once sent it becomes the
+    /// thread's timer there.
+    #[serde(default)]
+    pub expire_timer: Option<u32>,
+    /// Whether this message changes the thread's timer to `expire_timer`.
+    #[serde(default)]
+    pub expiration_update: bool,
+    /// URL every change of the delivery status is POSTed to.
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub status_callback: Option<Url>,
+    /// When the message is to be sent, if not right away.
+    #[serde(default)]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub send_at: Option<DateTime<Utc>>,
+}
+
+/// Text styles Signal clients render.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TextStyle {
+    Bold,
+    Italic,
+    Spoiler,
+    Strikethrough,
+    Monospace,
+}
+
+/// A style applied to part of the body.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct StyleRange {
+    pub style: TextStyle,
+    /// Offset in the body, in UTF-16 code units.
+    pub start: u32,
+    pub length: u32,
+}
+
+/// A mention of a contact, covering a placeholder in the body.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Mention {
+    #[schema(value_type = String)]
+    pub uuid: Uuid,
+    /// Offset of the placeholder in the body, in UTF-16 code units.
+    pub start: u32,
+    pub length: u32,
+}
+
+/// An earlier message that is replied to.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct QuoteTarget {
+    /// ACI of the author of the quoted message.
+    #[schema(value_type = String)]
+    pub author: Uuid,
+    /// The timestamp the quoted message was sent with.
+    pub timestamp: u64,
+}
+
+/// An emoji reaction to an earlier message, or the removal of one.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ReactionTarget {
+    pub emoji: String,
+    /// Whether an earlier reaction with the same emoji is taken back.
+    pub remove: bool,
+    /// ACI of the author of the message reacted to.
+    #[schema(value_type = String)]
+    pub author: Uuid,
+    /// The timestamp the message reacted to was sent with.
+    pub timestamp: u64,
+}
+
+/// A file stored for sending along with an [`Outgoing`] message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: u64,
+    pub content_type: String,
+    pub file_name: Option<String>,
+    pub size: usize,
+    /// The encoded `AttachmentPointer` once uploaded, so later sends reuse the upload.
+    #[serde(default)]
+    pub pointer: Option<Vec<u8>>,
+}
+
+/// A message accepted for delivery.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Entry {
+    pub id: u64,
+    #[schema(value_type = String)]
+    pub destination: Destination,
+    #[serde(flatten)]
+    pub message: Outgoing,
+    /// The timestamp the message is sent with, which identifies it in the thread.
+    ///
+    /// Assigned on the first attempt to send the entry rather than when it is accepted, so
+    /// scheduled messages are not sent backdated. Later attempts keep it.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    pub state: State,
+    pub attempts: u32,
+    pub last_error: Option<SendError>,
+    /// When a pending entry is due, if not right away: the time it was scheduled for, or
+    /// the next attempt after a failed one.
+    #[serde(default)]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: DateTime<Utc>,
+    /// When the entry was sent, or dead-lettered.
+    #[serde(default)]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// How far a message got to one of its recipients.
+///
+/// Declared in the order a message goes through, see [`DeliveryStatus::advances_to`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Queued,
+    /// Accepted by Signal.
+    Sent,
+    /// Acknowledged by a device of the recipient.
+    Delivered,
+    Read,
+    /// Not sent, as the entry was dead-lettered.
+    Failed,
+}
+
+impl DeliveryStatus {
+    /// Whether moving to `next` is progress. Receipts may arrive out of order, and a
+    /// late delivery receipt must not undo a read one.
+    pub fn advances_to(self, next: Self) -> bool {
+        match (self, next) {
+            (Self::Queued, Self::Failed) => true,
+            (Self::Failed, _) | (_, Self::Failed) => false,
+            (current, next) => next > current,
+        }
+    }
+}
+
+/// The delivery status of a message for one recipient.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct RecipientStatus {
+    pub status: DeliveryStatus,
+    #[schema(value_type = String, format = DateTime)]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Entry {
+    /// Whether the entry waits for the time it was scheduled for, without any attempt
+    /// to send it yet.
+    pub fn is_scheduled(&self) -> bool {
+        self.state == State::Pending && self.attempts == 0 && self.message.send_at.is_some()
+    }
+}
+
+/// What became of an entry after an attempt to send it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Sent,
+    /// Failed, and is attempted again at the given time.
+    Retry(DateTime<Utc>),
+    /// Failed for good, and was moved to the dead letters.
+    DeadLettered,
+    /// Held back by a proof-required challenge, until it is answered.
+    Held,
+}
+
+/// Persistent queue of outbound messages.
+///
+/// Entries are keyed by a big-endian id so iterating the tree yields them in the order
+/// they were accepted. Entries that run out of attempts are moved, under the same key,
+/// to a separate tree of dead letters. Attachments are kept apart from entries, with
+/// their content dropped once uploaded. Edits are tracked by mapping the timestamp of
+/// each edit to the timestamp of the message it replaces. The disappearing-messages timer
+/// of each thread is kept by destination.
+///
+/// Delivery statuses are kept apart from entries, as receipts update them while entries
+/// are being sent. They are found by entry id, or by the timestamp receipts refer to.
+///
+/// Sent entries and dead letters are kept for a retention period, see
+/// [`Outbox::prune_expired`].
+///
+/// The outbox is not encrypted, even when the Signal store is opened with a passphrase:
+/// message bodies and attachments waiting to be uploaded are stored in plain text.
+#[derive(Clone)]
+pub struct Outbox {
+    db: sled::Db,
+    entries: sled::Tree,
+    dead_letters: sled::Tree,
+    attachments: sled::Tree,
+    attachment_data: sled::Tree,
+    edits: sled::Tree,
+    expire_timers: sled::Tree,
+    deliveries: sled::Tree,
+    sent_timestamps: sled::Tree,
+    last_timestamp: Arc<AtomicU64>,
+}
+
+impl Outbox {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let db = sled::open(path)
+            .with_context(|| format!("failed to open outbox at {}", path.display()))?;
+        let entries = db.open_tree("entries")?;
+        let dead_letters = db.open_tree("dead_letters")?;
+        let attachments = db.open_tree("attachments")?;
+        let attachment_data = db.open_tree("attachment_data")?;
+        let edits = db.open_tree("edits")?;
+        let expire_timers = db.open_tree("expire_timers")?;
+        let deliveries = db.open_tree("deliveries")?;
+        let sent_timestamps = db.open_tree("sent_timestamps")?;
+
+        Ok(Self {
+            db,
+            entries,
+            dead_letters,
+            attachments,
+            attachment_data,
+            edits,
+            expire_timers,
+            deliveries,
+            sent_timestamps,
+            last_timestamp: Default::default(),
+        })
+    }
+
+    /// Opens a tree for records kept alongside the outbox.
+    pub fn open_tree(&self, name: &str) -> anyhow::Result<sled::Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
+
+    /// Stores a new pending entry, returning once it is durable.
+    ///
+    /// Edits, deletions, reactions and quotes of an edit are redirected to the original
+    /// message, as Signal clients only know messages by their original timestamp.
+    pub async fn push(&self, destination: Destination, mut message: Outgoing) -> anyhow::Result<Entry> {
+        if let Some(target) = message.edit {
+            message.edit = Some(self.original_timestamp(target)?);
+        }
+        if let Some(target) = message.delete {
+            message.delete = Some(self.original_timestamp(target)?);
+        }
+        if let Some(reaction) = &mut message.reaction {
+            reaction.timestamp = self.original_timestamp(reaction.timestamp)?;
+        }
+        if let Some(quote) = &mut message.quote {
+            quote.timestamp = self.original_timestamp(quote.timestamp)?;
+        }
+
+        let due = message.send_at;
+        let entry = Entry {
+            id: self.db.generate_id()?,
+            destination,
+            message,
+            timestamp: None,
+            state: State::Pending,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: due,
+            created_at: Utc::now(),
+            finished_at: None,
+        };
+        self.save(&entry)?;
+        self.db.flush_async().await?;
+
+        Ok(entry)
+    }
+
+    /// Returns the entry sent with `timestamp`, if it was sent from here.
+    pub fn sent_with(&self, timestamp: u64) -> anyhow::Result<Option<Entry>> {
+        let Some(id) = self.sent_timestamps.get(timestamp.to_be_bytes())? else {
+            return Ok(None);
+        };
+        let id = u64::from_be_bytes(id.as_ref().try_into().context("corrupt timestamp record")?);
+        self.find(id)
+    }
+
+    /// Returns an entry, whether still in the outbox or dead-lettered.
+    pub fn find(&self, id: u64) -> anyhow::Result<Option<Entry>> {
+        match self.get(id)? {
+            Some(entry) => Ok(Some(entry)),
+            None => self.dead_letter(id),
+        }
+    }
+
+    /// Returns the delivery status of an entry for each of its recipients.
+    pub fn delivery(&self, id: u64) -> anyhow::Result<BTreeMap<Uuid, RecipientStatus>> {
+        self.deliveries
+            .get(id.to_be_bytes())?
+            .map(|value| decode(&value))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Moves the delivery status of an entry to `status` for each of `recipients`,
+    /// returning the recipients it changed for.
+    ///
+    /// Recipients not seen before start out with `status`. Starting out queued does not
+    /// count as a change.
+    pub fn advance(
+        &self,
+        id: u64,
+        recipients: &[Uuid],
+        status: DeliveryStatus,
+    ) -> anyhow::Result<Vec<(Uuid, RecipientStatus)>> {
+        let key = id.to_be_bytes();
+        self.deliveries
+            .transaction(|deliveries| {
+                let mut delivery: BTreeMap<Uuid, RecipientStatus> = BTreeMap::new();
+                if let Some(value) = deliveries.get(&key[..])? {
+                    delivery = decode(&value).map_err(ConflictableTransactionError::Abort)?;
+                }
+
+                let updated = RecipientStatus {
+                    status,
+                    updated_at: Utc::now(),
+                };
+                let mut changes = Vec::new();
+                for recipient in recipients {
+                    let current = delivery.get(recipient).map(|r| r.status);
+                    if current.is_some_and(|current| !current.advances_to(status)) {
+                        continue;
+                    }
+                    delivery.insert(*recipient, updated);
+                    if status != DeliveryStatus::Queued {
+                        changes.push((*recipient, updated));
+                    }
+                }
+
+                let value = serde_json::to_vec(&delivery)
+                    .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                deliveries.insert(&key[..], value)?;
+                Ok(changes)
+            })
+            .map_err(|e| anyhow::anyhow!("failed to update delivery of entry {id}: {e:?}"))
+    }
+
+    /// Returns the timestamp of the message an edit replaced, or `timestamp` itself if it
+    /// is not an edit.
+    pub fn original_timestamp(&self, timestamp: u64) -> anyhow::Result<u64> {
+        match self.edits.get(timestamp.to_be_bytes())? {
+            Some(original) => Ok(u64::from_be_bytes(
+                original
+                    .as_ref()
+                    .try_into()
+                    .context("corrupt edit record")?,
+            )),
+            None => Ok(timestamp),
+        }
+    }
+
+    /// Returns the disappearing-messages timer of a thread, in seconds, if it has one.
+    pub fn expire_timer(&self, thread: &Destination) -> anyhow::Result<Option<u32>> {
+        match self.expire_timers.get(thread.to_string())? {
+            Some(seconds) => Ok(Some(u32::from_be_bytes(
+                seconds.as_ref().try_into().context("corrupt expire timer")?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the disappearing-messages timer of a thread, turning it off for `0`.
+    pub fn set_expire_timer(&self, thread: &Destination, seconds: u32) -> anyhow::Result<()> {
+        match seconds {
+            0 => self.expire_timers.remove(thread.to_string())?,
+            _ => self
+                .expire_timers
+                .insert(thread.to_string(), &seconds.to_be_bytes())?,
+        };
+        Ok(())
+    }
+
+    pub fn get(&self, id: u64) -> anyhow::Result<Option<Entry>> {
+        self.entries
+            .get(id.to_be_bytes())?
+            .map(|value| decode(&value))
+            .transpose()
+    }
+
+    /// Stores the content of an attachment for a message yet to be pushed.
+    pub fn store_attachment(
+        &self,
+        content_type: String,
+        file_name: Option<String>,
+        data: &[u8],
+    ) -> anyhow::Result<Attachment> {
+        let attachment = Attachment {
+            id: self.db.generate_id()?,
+            content_type,
+            file_name,
+            size: data.len(),
+            pointer: None,
+        };
+        self.attachment_data
+            .insert(attachment.id.to_be_bytes(), data)?;
+        self.save_attachment(&attachment)?;
+
+        Ok(attachment)
+    }
+
+    pub fn attachment(&self, id: u64) -> anyhow::Result<Option<Attachment>> {
+        self.attachments
+            .get(id.to_be_bytes())?
+            .map(|value| decode(&value))
+            .transpose()
+    }
+
+    pub fn attachment_data(&self, id: u64) -> anyhow::Result<Option<sled::IVec>> {
+        Ok(self.attachment_data.get(id.to_be_bytes())?)
+    }
+
+    /// Records where an attachment was uploaded to, and drops its content.
+    pub fn attachment_uploaded(
+        &self,
+        mut attachment: Attachment,
+        pointer: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        attachment.pointer = Some(pointer);
+        self.save_attachment(&attachment)?;
+        self.attachment_data.remove(attachment.id.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Lists the entries waiting for the time they were scheduled for, soonest first.
+    pub fn scheduled(&self) -> anyhow::Result<Vec<Entry>> {
+        let mut scheduled = Vec::new();
+        for value in self.entries.iter().values() {
+            let entry: Entry = decode(&value?)?;
+            if entry.is_scheduled() {
+                scheduled.push(entry);
+            }
+        }
+        scheduled.sort_by_key(|entry| entry.next_attempt_at);
+
+        Ok(scheduled)
+    }
+
+    /// Moves a scheduled entry to another time, returning it if it was still scheduled.
+    pub fn reschedule(&self, id: u64, at: DateTime<Utc>) -> anyhow::Result<Option<Entry>> {
+        self.swap_scheduled(id, |entry| {
+            let mut entry = entry.clone();
+            entry.message.send_at = Some(at);
+            entry.next_attempt_at = Some(at);
+            Some(entry)
+        })
+    }
+
+    /// Deletes a scheduled entry, along with its records, returning it if it was still
+    /// scheduled.
+    pub fn cancel(&self, id: u64) -> anyhow::Result<Option<Entry>> {
+        let Some(entry) = self.swap_scheduled(id, |_| None)? else {
+            return Ok(None);
+        };
+        self.forget(std::slice::from_ref(&entry))?;
+
+        Ok(Some(entry))
+    }
+
+    /// Replaces an entry that is still scheduled, or removes it when `replace` returns
+    /// `None`. Returns the replacement, or the removed entry.
+    fn swap_scheduled(
+        &self,
+        id: u64,
+        replace: impl Fn(&Entry) -> Option<Entry>,
+    ) -> anyhow::Result<Option<Entry>> {
+        let swapped = self.swap(id, |entry| entry.is_scheduled().then(|| replace(entry)))?;
+        Ok(swapped.map(|(entry, replacement)| replacement.unwrap_or(entry)))
+    }
+
+    /// Replaces an entry, or removes it when `replace` returns `Some(None)`, leaving it as
+    /// it is when `replace` returns `None`. Returns the entry and what replaced it, if it
+    /// was swapped.
+    ///
+    /// The service and the API change entries side by side, so the swap only happens if
+    /// the entry did not change in between, and `replace` is asked again otherwise.
+    fn swap(
+        &self,
+        id: u64,
+        replace: impl Fn(&Entry) -> Option<Option<Entry>>,
+    ) -> anyhow::Result<Option<(Entry, Option<Entry>)>> {
+        let key = id.to_be_bytes();
+        loop {
+            let Some(current) = self.entries.get(key)? else {
+                return Ok(None);
+            };
+            let entry: Entry = decode(&current)?;
+            let Some(replacement) = replace(&entry) else {
+                return Ok(None);
+            };
+
+            let value = replacement.as_ref().map(serde_json::to_vec).transpose()?;
+            if self
+                .entries
+                .compare_and_swap(key, Some(current), value)?
+                .is_ok()
+            {
+                return Ok(Some((entry, replacement)));
+            }
+        }
+    }
+
+    /// Marks a pending entry as in flight, returning it if it was pending and due.
+    ///
+    /// An entry rescheduled or cancelled since it was queued is left as it is. The first
+    /// attempt gives the entry its timestamp, recording it so receipts, edits and
+    /// deletions find the entry.
+    pub fn begin(&self, id: u64) -> anyhow::Result<Option<Entry>> {
+        let now = Utc::now();
+        let swapped = self.swap(id, |entry| {
+            let due = entry.next_attempt_at.is_none_or(|at| at <= now);
+            (entry.state == State::Pending && due).then(|| {
+                let mut entry = entry.clone();
+                entry.state = State::InFlight;
+                entry.attempts += 1;
+                entry.timestamp = entry.timestamp.or_else(|| Some(self.next_timestamp()));
+                Some(entry)
+            })
+        })?;
+        let Some((before, Some(entry))) = swapped else {
+            return Ok(None);
+        };
+
+        if let (None, Some(timestamp)) = (before.timestamp, entry.timestamp) {
+            if let Some(original) = entry.message.edit {
+                self.edits
+                    .insert(timestamp.to_be_bytes(), &original.to_be_bytes())?;
+            }
+            self.sent_timestamps
+                .insert(timestamp.to_be_bytes(), &id.to_be_bytes())?;
+        }
+        Ok(Some(entry))
+    }
+
+    /// Saves the outcome of an in-flight entry, if it is still in flight.
+    fn settle(&self, entry: &Entry) -> anyhow::Result<()> {
+        let swapped = self.swap(entry.id, |current| {
+            (current.state == State::InFlight).then(|| Some(entry.clone()))
+        })?;
+        if swapped.is_none() {
+            anyhow::bail!("entry {} is no longer in flight", entry.id);
+        }
+        Ok(())
+    }
+
+    /// Records the outcome of sending an in-flight entry, applying `policy` on failure.
+    pub fn finish(
+        &self,
+        mut entry: Entry,
+        result: Result<(), SendError>,
+        policy: &RetryPolicy,
+    ) -> anyhow::Result<Outcome> {
+        let error = match result {
+            Ok(()) => {
+                entry.state = State::Sent;
+                entry.last_error = None;
+                entry.next_attempt_at = None;
+                entry.finished_at = Some(Utc::now());
+                self.settle(&entry)?;
+                return Ok(Outcome::Sent);
+            }
+            Err(e) => e,
+        };
+
+        // Not the entry's fault, so the attempt is given back.
+        if let SendError::ProofRequired { .. } = error {
+            entry.state = State::Pending;
+            entry.attempts = entry.attempts.saturating_sub(1);
+            entry.last_error = Some(error);
+            entry.next_attempt_at = None;
+            self.settle(&entry)?;
+            return Ok(Outcome::Held);
+        }
+
+        match policy.next_attempt(entry.attempts, &error) {
+            Some(delay) => {
+                let at = Utc::now() + chrono::Duration::from_std(delay)?;
+                entry.state = State::Pending;
+                entry.last_error = Some(error);
+                entry.next_attempt_at = Some(at);
+                self.settle(&entry)?;
+                Ok(Outcome::Retry(at))
+            }
+            None => {
+                entry.state = State::Failed;
+                entry.last_error = Some(error);
+                entry.next_attempt_at = None;
+                entry.finished_at = Some(Utc::now());
+                self.bury(&entry)?;
+                Ok(Outcome::DeadLettered)
+            }
+        }
+    }
+
+    /// Returns the entries still to be sent, oldest first, with the time they are due.
+    ///
+    /// Entries left in flight by a previous run may or may not have reached Signal. They
+    /// are moved back to pending, so delivery is at least once.
+    pub fn recover(&self) -> anyhow::Result<Vec<(u64, Option<DateTime<Utc>>)>> {
+        let mut pending = Vec::new();
+        for item in self.entries.iter() {
+            let (_, value) = item?;
+            let mut entry: Entry = decode(&value)?;
+            match entry.state {
+                State::Pending => {}
+                State::InFlight => {
+                    entry.state = State::Pending;
+                    self.settle(&entry)?;
+                }
+                State::Sent | State::Failed => continue,
+            }
+            pending.push((entry.id, entry.next_attempt_at));
+        }
+
+        Ok(pending)
+    }
+
+    /// Lists dead-lettered entries, oldest first.
+    pub fn dead_letters(&self) -> anyhow::Result<Vec<Entry>> {
+        self.dead_letters
+            .iter()
+            .values()
+            .map(|value| decode(&value?))
+            .collect()
+    }
+
+    pub fn dead_letter(&self, id: u64) -> anyhow::Result<Option<Entry>> {
+        self.dead_letters
+            .get(id.to_be_bytes())?
+            .map(|value| decode(&value))
+            .transpose()
+    }
+
+    /// Moves a dead-lettered entry back to the outbox with a fresh set of attempts.
+    pub fn replay(&self, id: u64) -> anyhow::Result<Option<Entry>> {
+        let Some(mut entry) = self.dead_letter(id)? else {
+            return Ok(None);
+        };
+        entry.state = State::Pending;
+        entry.attempts = 0;
+        entry.next_attempt_at = None;
+
+        let key = id.to_be_bytes();
+        let value = serde_json::to_vec(&entry)?;
+        (&self.entries, &self.dead_letters)
+            .transaction(|(entries, dead_letters)| {
+                dead_letters.remove(&key[..])?;
+                entries.insert(&key[..], value.as_slice())?;
+                Ok::<_, ConflictableTransactionError>(())
+            })
+            .map_err(|e| anyhow::anyhow!("failed to replay dead letter {id}: {e:?}"))?;
+
+        Ok(Some(entry))
+    }
+
+    /// Deletes a dead-lettered entry, along with its records, returning whether it existed.
+    pub fn purge(&self, id: u64) -> anyhow::Result<bool> {
+        let Some(value) = self.dead_letters.remove(id.to_be_bytes())? else {
+            return Ok(false);
+        };
+        self.forget(&[decode(&value)?])?;
+        Ok(true)
+    }
+
+    /// Deletes all dead-lettered entries, along with their records, returning how many
+    /// there were.
+    pub fn purge_all(&self) -> anyhow::Result<usize> {
+        let mut purged = Vec::new();
+        for key in self.dead_letters.iter().keys() {
+            // Skip entries replayed since they were listed.
+            if let Some(value) = self.dead_letters.remove(key?)? {
+                purged.push(decode(&value)?);
+            }
+        }
+        self.forget(&purged)?;
+        Ok(purged.len())
+    }
+
+    /// Deletes sent entries and dead letters that finished before `cutoff`, along with
+    /// their delivery statuses and the attachments no other entry refers to. Returns how
+    /// many entries were deleted.
+    pub fn prune(&self, cutoff: DateTime<Utc>) -> anyhow::Result<usize> {
+        let expired = |entry: &Entry| {
+            matches!(entry.state, State::Sent | State::Failed)
+                && entry.finished_at.unwrap_or(entry.created_at) < cutoff
+        };
+
+        let mut pruned = Vec::new();
+        for tree in [&self.entries, &self.dead_letters] {
+            for item in tree.iter() {
+                let (key, value) = item?;
+                let entry: Entry = decode(&value)?;
+                if !expired(&entry) {
+                    continue;
+                }
+                // Skip entries replayed since they were read.
+                if tree
+                    .compare_and_swap(key, Some(value), None::<&[u8]>)?
+                    .is_ok()
+                {
+                    pruned.push(entry);
+                }
+            }
+        }
+
+        self.forget(&pruned)?;
+        Ok(pruned.len())
+    }
+
+    /// Deletes the records kept for entries that were removed: their delivery statuses,
+    /// timestamps and edits, and the attachments no other entry refers to.
+    fn forget(&self, removed: &[Entry]) -> anyhow::Result<()> {
+        let mut attachments = HashSet::new();
+        for entry in removed {
+            self.deliveries.remove(entry.id.to_be_bytes())?;
+            if let Some(timestamp) = entry.timestamp {
+                self.sent_timestamps.remove(timestamp.to_be_bytes())?;
+                self.edits.remove(timestamp.to_be_bytes())?;
+            }
+            attachments.extend(entry.message.attachments.iter().copied());
+        }
+
+        // Broadcasts share attachments between entries.
+        if !attachments.is_empty() {
+            for tree in [&self.entries, &self.dead_letters] {
+                for value in tree.iter().values() {
+                    let entry: Entry = decode(&value?)?;
+                    for id in &entry.message.attachments {
+                        attachments.remove(id);
+                    }
+                }
+            }
+            self.discard_attachments(attachments)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes sent entries and dead letters once `retention` has passed since they
+    /// finished, checking every [`PRUNE_INTERVAL`], forever.
+    pub async fn prune_expired(self, retention: Duration) {
+        let Ok(retention) = chrono::Duration::from_std(retention) else {
+            error!("outbox retention of {retention:?} is out of range, nothing is pruned");
+            return;
+        };
+
+        let mut ticks = interval(PRUNE_INTERVAL);
+        loop {
+            ticks.tick().await;
+
+            match self.prune(Utc::now() - retention) {
+                Ok(0) => {}
+                Ok(pruned) => info!("pruned {pruned} finished outbox entries"),
+                Err(e) => error!("failed to prune outbox: {e}"),
+            }
+        }
+    }
+
+    /// Deletes stored attachments, whether uploaded or not.
+    pub fn discard_attachments(&self, ids: impl IntoIterator<Item = u64>) -> anyhow::Result<()> {
+        for id in ids {
+            self.attachments.remove(id.to_be_bytes())?;
+            self.attachment_data.remove(id.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Moves an entry from the outbox to the dead letters, unless it was removed.
+    fn bury(&self, entry: &Entry) -> anyhow::Result<()> {
+        let key = entry.id.to_be_bytes();
+        let value = serde_json::to_vec(entry)?;
+        (&self.entries, &self.dead_letters)
+            .transaction(|(entries, dead_letters)| {
+                if entries.remove(&key[..])?.is_none() {
+                    return Err(ConflictableTransactionError::Abort(()));
+                }
+                dead_letters.insert(&key[..], value.as_slice())?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| anyhow::anyhow!("failed to dead-letter entry {}: {e:?}", entry.id))?;
+
+        Ok(())
+    }
+
+    fn save(&self, entry: &Entry) -> anyhow::Result<()> {
+        self.entries
+            .insert(entry.id.to_be_bytes(), serde_json::to_vec(entry)?)?;
+        Ok(())
+    }
+
+    fn save_attachment(&self, attachment: &Attachment) -> anyhow::Result<()> {
+        self.attachments
+            .insert(attachment.id.to_be_bytes(), serde_json::to_vec(attachment)?)?;
+        Ok(())
+    }
+
+    /// Current time in milliseconds, bumped so no two entries share a timestamp.
+    pub fn next_timestamp(&self) -> u64 {
+        let now = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+
+        let mut last = self.last_timestamp.load(Ordering::Relaxed);
+        loop {
+            let next = now.max(last + 1);
+            match self.last_timestamp.compare_exchange_weak(
+                last,
+                next,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return next,
+                Err(current) => last = current,
+            }
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(value: &[u8]) -> anyhow::Result<T> {
+    serde_json::from_slice(value).context("failed to decode outbox record")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::retry::ErrorClass;
+
+    fn open() -> (tempfile::TempDir, Outbox) {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path()).unwrap();
+        (dir, outbox)
+    }
+
+    /// Retries network failures right away, twice at most.
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            jitter: 0.0,
+            retry_on: vec![ErrorClass::Network],
+        }
+    }
+
+    fn contact() -> Destination {
+        Destination::Contact(Uuid::from_u128(1))
+    }
+
+    fn text(body: &str) -> Outgoing {
+        Outgoing {
+            body: Some(body.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn network() -> Result<(), SendError> {
+        Err(SendError::Network("connection reset".to_owned()))
+    }
+
+    #[test]
+    fn assigns_the_timestamp_on_the_first_attempt() {
+        let (_dir, outbox) = open();
+        let due = Utc::now() - chrono::Duration::seconds(1);
+        let message = Outgoing {
+            send_at: Some(due),
+            ..text("hi")
+        };
+        let entry = block_on(outbox.push(contact(), message)).unwrap();
+        assert_eq!(entry.timestamp, None);
+
+        let accepted = outbox.next_timestamp();
+        let begun = outbox.begin(entry.id).unwrap().unwrap();
+        let timestamp = begun.timestamp.unwrap();
+        assert!(timestamp > accepted);
+        assert_eq!(outbox.sent_with(timestamp).unwrap().unwrap().id, entry.id);
+
+        // Kept for the attempts after.
+        outbox.finish(begun, network(), &policy()).unwrap();
+        let retried = outbox.begin(entry.id).unwrap().unwrap();
+        assert_eq!((retried.timestamp, retried.attempts), (Some(timestamp), 2));
+    }
+
+    #[test]
+    fn does_not_begin_entries_rescheduled_or_cancelled_since_they_were_queued() {
+        let (_dir, outbox) = open();
+        let due = Utc::now() - chrono::Duration::seconds(1);
+        let message = Outgoing {
+            send_at: Some(due),
+            ..text("hi")
+        };
+        let entry = block_on(outbox.push(contact(), message)).unwrap();
+
+        let later = Utc::now() + chrono::Duration::hours(1);
+        outbox.reschedule(entry.id, later).unwrap().unwrap();
+        assert!(outbox.begin(entry.id).unwrap().is_none());
+        let unchanged = outbox.get(entry.id).unwrap().unwrap();
+        assert!(unchanged.is_scheduled());
+        assert_eq!(unchanged.next_attempt_at, Some(later));
+
+        outbox.cancel(entry.id).unwrap().unwrap();
+        assert!(outbox.begin(entry.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn sends_pending_entries_once() {
+        let (_dir, outbox) = open();
+        let entry = block_on(outbox.push(contact(), text("hi"))).unwrap();
+        assert_eq!((entry.state, entry.attempts), (State::Pending, 0));
+
+        let begun = outbox.begin(entry.id).unwrap().unwrap();
+        assert_eq!((begun.state, begun.attempts), (State::InFlight, 1));
+        assert!(outbox.begin(entry.id).unwrap().is_none());
+
+        let outcome = outbox.finish(begun, Ok(()), &policy()).unwrap();
+        assert_eq!(outcome, Outcome::Sent);
+        let sent = outbox.get(entry.id).unwrap().unwrap();
+        assert_eq!(sent.state, State::Sent);
+        assert!(sent.finished_at.is_some());
+        assert!(outbox.begin(entry.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn retries_and_then_dead_letters() {
+        let (_dir, outbox) = open();
+        let entry = block_on(outbox.push(contact(), text("hi"))).unwrap();
+
+        let begun = outbox.begin(entry.id).unwrap().unwrap();
+        let Outcome::Retry(at) = outbox.finish(begun, network(), &policy()).unwrap() else {
+            panic!("not retried");
+        };
+        let retried = outbox.get(entry.id).unwrap().unwrap();
+        assert_eq!(retried.state, State::Pending);
+        assert_eq!(retried.next_attempt_at, Some(at));
+        assert!(matches!(retried.last_error, Some(SendError::Network(_))));
+
+        let begun = outbox.begin(entry.id).unwrap().unwrap();
+        let outcome = outbox.finish(begun, network(), &policy()).unwrap();
+        assert_eq!(outcome, Outcome::DeadLettered);
+        assert!(outbox.get(entry.id).unwrap().is_none());
+        let dead = outbox.dead_letter(entry.id).unwrap().unwrap();
+        assert_eq!((dead.state, dead.attempts), (State::Failed, 2));
+        assert_eq!(outbox.find(entry.id).unwrap().unwrap().state, State::Failed);
+    }
+
+    #[test]
+    fn dead_letters_errors_that_are_not_retried() {
+        let (_dir, outbox) = open();
+        let entry = block_on(outbox.push(contact(), text("hi"))).unwrap();
+
+        let begun = outbox.begin(entry.id).unwrap().unwrap();
+        let error = Err(SendError::UnknownRecipient);
+        let outcome = outbox.finish(begun, error, &policy()).unwrap();
+        assert_eq!(outcome, Outcome::DeadLettered);
+        assert_eq!(outbox.dead_letters().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn recovers_entries_left_in_flight() {
+        let (_dir, outbox) = open();
+        let sent = block_on(outbox.push(contact(), text("sent"))).unwrap();
+        let begun = outbox.begin(sent.id).unwrap().unwrap();
+        outbox.finish(begun, Ok(()), &policy()).unwrap();
+        let in_flight = block_on(outbox.push(contact(), text("in flight"))).unwrap();
+        outbox.begin(in_flight.id).unwrap().unwrap();
+        let later = Utc::now() + chrono::Duration::hours(1);
+        let message = Outgoing {
+            send_at: Some(later),
+            ..text("scheduled")
+        };
+        let scheduled = block_on(outbox.push(contact(), message)).unwrap();
+
+        let pending = outbox.recover().unwrap();
+        assert_eq!(pending, [(in_flight.id, None), (scheduled.id, Some(later))]);
+        let recovered = outbox.get(in_flight.id).unwrap().unwrap();
+        assert_eq!((recovered.state, recovered.attempts), (State::Pending, 1));
+    }
+
+    #[test]
+    fn replays_dead_letters_with_fresh_attempts() {
+        let (_dir, outbox) = open();
+        let entry = block_on(outbox.push(contact(), text("hi"))).unwrap();
+        let begun = outbox.begin(entry.id).unwrap().unwrap();
+        let error = Err(SendError::UnknownRecipient);
+        outbox.finish(begun, error, &policy()).unwrap();
+
+        let replayed = outbox.replay(entry.id).unwrap().unwrap();
+        assert_eq!((replayed.state, replayed.attempts), (State::Pending, 0));
+        assert!(outbox.dead_letter(entry.id).unwrap().is_none());
+        assert_eq!(outbox.get(entry.id).unwrap().unwrap().state, State::Pending);
+        assert!(outbox.replay(entry.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn prunes_finished_entries_and_the_attachments_only_they_use() {
+        let (_dir, outbox) = open();
+        let png = || "image/png".to_owned();
+        let shared = outbox.store_attachment(png(), None, b"a").unwrap();
+        let own = outbox.store_attachment(png(), None, b"b").unwrap();
+        let message = |attachments| Outgoing {
+            attachments,
+            ..text("hi")
+        };
+        let sent = block_on(outbox.push(contact(), message(vec![shared.id, own.id]))).unwrap();
+        let waiting = block_on(outbox.push(contact(), message(vec![shared.id]))).unwrap();
+        let begun = outbox.begin(sent.id).unwrap().unwrap();
+        let timestamp = begun.timestamp.unwrap();
+        outbox.finish(begun, Ok(()), &policy()).unwrap();
+        outbox
+            .advance(sent.id, &[Uuid::from_u128(1)], DeliveryStatus::Sent)
+            .unwrap();
+
+        let hour = chrono::Duration::hours(1);
+        assert_eq!(outbox.prune(Utc::now() - hour).unwrap(), 0);
+        assert_eq!(outbox.prune(Utc::now() + hour).unwrap(), 1);
+        assert!(outbox.get(sent.id).unwrap().is_none());
+        assert!(outbox.get(waiting.id).unwrap().is_some());
+        assert!(outbox.delivery(sent.id).unwrap().is_empty());
+        assert!(outbox.sent_with(timestamp).unwrap().is_none());
+        assert!(outbox.attachment(own.id).unwrap().is_none());
+        assert!(outbox.attachment_data(own.id).unwrap().is_none());
+        assert!(outbox.attachment(shared.id).unwrap().is_some());
+    }
+
+    #[test]
+    fn delivery_status_only_moves_forward() {
+        let (_dir, outbox) = open();
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let advance = |recipients: &[Uuid], status| {
+            let changes = outbox.advance(7, recipients, status).unwrap();
+            changes
+                .into_iter()
+                .map(|(recipient, _)| recipient)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(advance(&[a, b], DeliveryStatus::Queued), []);
+        assert_eq!(advance(&[a], DeliveryStatus::Read), [a]);
+        // A late delivery receipt does not undo the read one.
+        assert_eq!(advance(&[a, b], DeliveryStatus::Delivered), [b]);
+        assert_eq!(advance(&[a], DeliveryStatus::Failed), []);
+
+        let delivery = outbox.delivery(7).unwrap();
+        assert_eq!(delivery[&a].status, DeliveryStatus::Read);
+        assert_eq!(delivery[&b].status, DeliveryStatus::Delivered);
+    }
+
+    #[test]
+    fn redirects_targets_of_edits_to_the_original() {
+        let (_dir, outbox) = open();
+        let send = |message| {
+            let entry = block_on(outbox.push(contact(), message)).unwrap();
+            let begun = outbox.begin(entry.id).unwrap().unwrap();
+            outbox.finish(begun.clone(), Ok(()), &policy()).unwrap();
+            begun
+        };
+        let original = send(text("hi")).timestamp.unwrap();
+        let edit = send(Outgoing {
+            edit: Some(original),
+            ..text("hello")
+        });
+        assert_eq!(edit.message.edit, Some(original));
+        let edited = edit.timestamp.unwrap();
+        assert_eq!(outbox.original_timestamp(edited).unwrap(), original);
+
+        let author = Uuid::from_u128(1);
+        let message = Outgoing {
+            edit: Some(edited),
+            delete: Some(edited),
+            reaction: Some(ReactionTarget {
+                emoji: "👍".to_owned(),
+                remove: false,
+                author,
+                timestamp: edited,
+            }),
+            quote: Some(QuoteTarget {
+                author,
+                timestamp: edited,
+            }),
+            ..text("again")
+        };
+        let entry = block_on(outbox.push(contact(), message)).unwrap();
+        assert_eq!(entry.message.edit, Some(original));
+        assert_eq!(entry.message.delete, Some(original));
+        assert_eq!(entry.message.reaction.unwrap().timestamp, original);
+        assert_eq!(entry.message.quote.unwrap().timestamp, original);
+    }
+}
@@ -0,0 +1,163 @@
+use axum::{
+    extract::{
+        multipart::MultipartRejection,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+    },
+    http::header,
+    response::{AppendHeaders, IntoResponse, Response},
+    Json,
+};
+use hyper::StatusCode;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::command::SendError;
+
+/// Media type of [`Problem`] responses.
+pub const CONTENT_TYPE: &str = "application/problem+json";
+
+/// An error response following RFC 7807 (problem details for HTTP APIs).
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    /// URI identifying the problem type.
+    #[serde(rename = "type")]
+    #[schema(example = "urn:signal-rest:problem:invalid-destination")]
+    kind: String,
+    /// Short, human-readable summary of the problem type.
+    title: String,
+    /// HTTP status code of the response.
+    status: u16,
+    /// Explanation specific to this occurrence of the problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    /// Seconds the client should wait before trying again, sent as `Retry-After`.
+    #[serde(skip)]
+    retry_after: Option<u64>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, kind: &str, title: impl Into<String>) -> Self {
+        Self {
+            kind: format!("urn:signal-rest:problem:{kind}"),
+            title: title.into(),
+            status: status.as_u16(),
+            detail: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    pub fn bad_request(kind: &str, detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, kind, "Bad request").with_detail(detail)
+    }
+
+    pub fn unprocessable(kind: &str, detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, kind, "Unprocessable entity")
+            .with_detail(detail)
+    }
+
+    pub fn not_found(kind: &str, detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, kind, "Not found").with_detail(detail)
+    }
+
+    pub fn internal(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", "Internal server error")
+            .with_detail(detail)
+    }
+
+    pub fn storage(e: anyhow::Error) -> Self {
+        Self::internal(format!("outbox storage failure: {e}"))
+    }
+
+    pub fn unavailable(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", "Service unavailable")
+            .with_detail(detail)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let retry_after = self
+            .retry_after
+            .map(|seconds| (header::RETRY_AFTER, seconds.to_string()));
+        (
+            self.status(),
+            [(header::CONTENT_TYPE, CONTENT_TYPE)],
+            AppendHeaders(retry_after),
+            Json(self),
+        )
+            .into_response()
+    }
+}
+
+impl From<SendError> for Problem {
+    fn from(e: SendError) -> Self {
+        let (status, kind, title) = match e {
+            SendError::UnknownRecipient => {
+                (StatusCode::NOT_FOUND, "unknown-recipient", "Unknown recipient")
+            }
+            SendError::RateLimited => (StatusCode::TOO_MANY_REQUESTS, "rate-limited", "Rate limited"),
+            SendError::ProofRequired { .. } => {
+                (StatusCode::TOO_MANY_REQUESTS, "proof-required", "Proof required")
+            }
+            SendError::UntrustedIdentity => {
+                (StatusCode::CONFLICT, "untrusted-identity", "Untrusted identity")
+            }
+            SendError::Network(_) => (StatusCode::BAD_GATEWAY, "network", "Signal unreachable"),
+            SendError::Other(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Internal server error",
+            ),
+        };
+
+        Self::new(status, kind, title).with_detail(e.to_string())
+    }
+}
+
+impl From<JsonRejection> for Problem {
+    fn from(rejection: JsonRejection) -> Self {
+        let status = rejection.status();
+        let kind = if status == StatusCode::UNPROCESSABLE_ENTITY {
+            "invalid-body"
+        } else {
+            "malformed-body"
+        };
+
+        Self::new(status, kind, "Invalid request body").with_detail(rejection.body_text())
+    }
+}
+
+impl From<MultipartRejection> for Problem {
+    fn from(rejection: MultipartRejection) -> Self {
+        Self::new(rejection.status(), "malformed-multipart", "Invalid request body")
+            .with_detail(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for Problem {
+    fn from(rejection: PathRejection) -> Self {
+        Self::new(rejection.status(), "invalid-path", "Invalid path parameter")
+            .with_detail(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for Problem {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(rejection.status(), "invalid-query", "Invalid query parameter")
+            .with_detail(rejection.body_text())
+    }
+}
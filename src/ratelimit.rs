@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::destination::Destination;
+
+/// Destination buckets kept before full ones are dropped.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// How long reserved account tokens are kept for a message that does not come back, e.g.
+/// because it was cancelled.
+const RESERVATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// A token bucket size and refill rate, given as `RATE[/BURST]`: messages per minute,
+/// and how many can be sent at once. The burst defaults to the rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub per_minute: f64,
+    pub burst: f64,
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rate, burst) = match s.split_once('/') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let per_minute: f64 = rate
+            .trim()
+            .parse()
+            .map_err(|e| format!("rate {rate:?}: {e}"))?;
+        let burst: f64 = match burst {
+            Some(burst) => burst
+                .trim()
+                .parse()
+                .map_err(|e| format!("burst {burst:?}: {e}"))?,
+            None => per_minute.max(1.0),
+        };
+
+        if !(per_minute > 0.0 && per_minute.is_finite() && burst >= 1.0 && burst.is_finite()) {
+            return Err(format!(
+                "{s:?} needs a positive rate and a burst of at least 1"
+            ));
+        }
+        Ok(Self { per_minute, burst })
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.per_minute, self.burst)
+    }
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+#[group(skip)]
+pub struct RateLimitArguments {
+    #[clap(
+        long,
+        env,
+        help = "Messages per minute across all destinations, as RATE[/BURST]"
+    )]
+    pub rate_limit_global: Option<Limit>,
+
+    #[clap(
+        long,
+        env,
+        help = "Recipients per minute reached from the account, as RATE[/BURST]; a group message counts once per member"
+    )]
+    pub rate_limit_account: Option<Limit>,
+
+    #[clap(
+        long,
+        env,
+        help = "Messages per minute to each contact or group, as RATE[/BURST]"
+    )]
+    pub rate_limit_destination: Option<Limit>,
+}
+
+/// Tokens left in a bucket, as of the last time it was looked at.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &Limit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_minute / 60.0).min(limit.burst);
+        self.updated = now;
+    }
+
+    /// How long until `cost` tokens are available. Costs above the burst only wait for
+    /// a full bucket, or they would never pass.
+    fn wait(&self, limit: &Limit, cost: f64) -> Duration {
+        let missing = cost.min(limit.burst) - self.tokens;
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing * 60.0 / limit.per_minute)
+    }
+
+    fn take(&mut self, cost: f64) {
+        self.tokens = (self.tokens - cost).max(0.0);
+    }
+
+    /// Takes `cost` tokens ahead of time, running into debt, and returns when the debt is
+    /// paid off.
+    fn reserve(&mut self, limit: &Limit, cost: f64, now: Instant) -> Instant {
+        self.tokens -= cost.min(limit.burst);
+        now + Duration::from_secs_f64((-self.tokens).max(0.0) * 60.0 / limit.per_minute)
+    }
+}
+
+/// Token buckets limiting how fast messages are sent: globally, from the account and to
+/// each destination.
+///
+/// A message goes out only once every bucket it draws from has enough tokens. Until then
+/// nothing is taken, so a message held back by one bucket does not drain the others.
+///
+/// The account bucket is the exception: a message it holds back reserves its tokens right
+/// away, running the bucket into debt, and goes out once the debt is paid off. Messages
+/// held back later wait behind it, so a group message costing one token per member is not
+/// starved by a stream of cheaper messages.
+#[derive(Debug)]
+pub struct RateLimiter {
+    global: Option<(Limit, Bucket)>,
+    account: Option<(Limit, Bucket)>,
+    /// When the account tokens reserved for each held back message are paid for, by
+    /// outbox entry id.
+    reservations: HashMap<u64, Instant>,
+    destination: Option<Limit>,
+    destinations: HashMap<Destination, Bucket>,
+}
+
+impl From<RateLimitArguments> for RateLimiter {
+    fn from(args: RateLimitArguments) -> Self {
+        let now = Instant::now();
+        let bucket = |limit: Limit| (limit, Bucket::full(&limit, now));
+        Self {
+            global: args.rate_limit_global.map(bucket),
+            account: args.rate_limit_account.map(bucket),
+            reservations: HashMap::new(),
+            destination: args.rate_limit_destination,
+            destinations: HashMap::new(),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Takes the tokens for outbox entry `id` to `destination` reaching `recipients`
+    /// accounts, or returns how long to wait before trying again.
+    pub fn acquire(
+        &mut self,
+        id: u64,
+        destination: &Destination,
+        recipients: usize,
+    ) -> Option<Duration> {
+        self.acquire_at(Instant::now(), id, destination, recipients)
+    }
+
+    fn acquire_at(
+        &mut self,
+        now: Instant,
+        id: u64,
+        destination: &Destination,
+        recipients: usize,
+    ) -> Option<Duration> {
+        let recipients = recipients.max(1) as f64;
+        self.reservations
+            .retain(|_, paid| now.saturating_duration_since(*paid) < RESERVATION_TIMEOUT);
+
+        if let Some(limit) = &self.destination {
+            if self.destinations.len() > MAX_IDLE_BUCKETS {
+                self.destinations.retain(|_, bucket| {
+                    bucket.refill(limit, now);
+                    bucket.tokens < limit.burst
+                });
+            }
+        }
+
+        let mut wait = Duration::ZERO;
+        if let Some((limit, bucket)) = &mut self.global {
+            bucket.refill(limit, now);
+            wait = wait.max(bucket.wait(limit, 1.0));
+        }
+        let reserved = self.reservations.get(&id).copied();
+        if let Some((limit, bucket)) = &mut self.account {
+            bucket.refill(limit, now);
+            let paid = match reserved {
+                Some(paid) => paid,
+                None if bucket.wait(limit, recipients).is_zero() => now,
+                None => {
+                    let paid = bucket.reserve(limit, recipients, now);
+                    self.reservations.insert(id, paid);
+                    paid
+                }
+            };
+            wait = wait.max(paid.saturating_duration_since(now));
+        }
+        let destination_bucket = self.destination.map(|limit| {
+            let bucket = self
+                .destinations
+                .entry(destination.clone())
+                .or_insert_with(|| Bucket::full(&limit, now));
+            bucket.refill(&limit, now);
+            wait = wait.max(bucket.wait(&limit, 1.0));
+            bucket
+        });
+
+        if !wait.is_zero() {
+            return Some(wait);
+        }
+
+        if let Some(bucket) = destination_bucket {
+            bucket.take(1.0);
+        }
+        if let Some((_, bucket)) = &mut self.global {
+            bucket.take(1.0);
+        }
+        if let Some((_, bucket)) = &mut self.account {
+            if self.reservations.remove(&id).is_none() {
+                bucket.take(recipients);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use presage::prelude::Uuid;
+
+    use super::*;
+
+    fn limit(per_minute: f64, burst: f64) -> Limit {
+        Limit { per_minute, burst }
+    }
+
+    fn account_limiter(limit: Limit) -> RateLimiter {
+        RateLimiter::from(RateLimitArguments {
+            rate_limit_global: None,
+            rate_limit_account: Some(limit),
+            rate_limit_destination: None,
+        })
+    }
+
+    #[test]
+    fn parses_rate_and_burst() {
+        assert_eq!("30".parse(), Ok(limit(30.0, 30.0)));
+        assert_eq!("30/5".parse(), Ok(limit(30.0, 5.0)));
+        assert_eq!(" 0.5 / 2 ".parse(), Ok(limit(0.5, 2.0)));
+        // The burst is at least one message, or nothing would ever pass.
+        assert_eq!("0.5".parse(), Ok(limit(0.5, 1.0)));
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        for s in [
+            "", "x", "0", "-1", "inf", "NaN", "10/", "10/0", "10/0.5", "10/x",
+        ] {
+            assert!(s.parse::<Limit>().is_err(), "{s:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn refills_buckets_over_time() {
+        let limit = limit(60.0, 2.0);
+        let start = Instant::now();
+        let mut bucket = Bucket::full(&limit, start);
+        assert_eq!(bucket.wait(&limit, 2.0), Duration::ZERO);
+
+        bucket.take(2.0);
+        assert_eq!(bucket.wait(&limit, 1.0), Duration::from_secs(1));
+
+        bucket.refill(&limit, start + Duration::from_millis(500));
+        assert_eq!(bucket.wait(&limit, 1.0), Duration::from_millis(500));
+
+        bucket.refill(&limit, start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn costs_above_the_burst_wait_for_a_full_bucket() {
+        let limit = limit(60.0, 2.0);
+        let start = Instant::now();
+        let mut bucket = Bucket::full(&limit, start);
+        assert_eq!(bucket.wait(&limit, 10.0), Duration::ZERO);
+
+        bucket.take(10.0);
+        assert_eq!(bucket.tokens, 0.0);
+        assert_eq!(bucket.wait(&limit, 10.0), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn group_messages_are_not_starved_by_cheaper_ones() {
+        let mut limiter = account_limiter(limit(60.0, 4.0));
+        let contact = Destination::Contact(Uuid::from_u128(1));
+        let group = Destination::Group([0; 32]);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        for id in 0..4 {
+            assert_eq!(limiter.acquire_at(start, id, &contact, 1), None);
+        }
+
+        // The group waits for all four tokens, reserving them.
+        assert_eq!(
+            limiter.acquire_at(start, 10, &group, 4),
+            Some(Duration::from_secs(4))
+        );
+        // Tokens refilled meanwhile are not taken by cheaper messages.
+        assert_eq!(
+            limiter.acquire_at(at(1), 11, &contact, 1),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(limiter.acquire_at(at(4), 10, &group, 4), None);
+        assert_eq!(
+            limiter.acquire_at(at(4), 12, &contact, 1),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(limiter.acquire_at(at(5), 11, &contact, 1), None);
+        assert_eq!(limiter.acquire_at(at(6), 12, &contact, 1), None);
+    }
+
+    #[test]
+    fn forgets_reservations_of_messages_that_do_not_come_back() {
+        let mut limiter = account_limiter(limit(60.0, 1.0));
+        let contact = Destination::Contact(Uuid::from_u128(1));
+        let start = Instant::now();
+
+        assert_eq!(limiter.acquire_at(start, 0, &contact, 1), None);
+        assert!(limiter.acquire_at(start, 1, &contact, 1).is_some());
+
+        let later = start + RESERVATION_TIMEOUT + Duration::from_secs(2);
+        assert_eq!(limiter.acquire_at(later, 2, &contact, 1), None);
+        assert!(limiter.reservations.is_empty());
+    }
+}
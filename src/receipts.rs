@@ -0,0 +1,22 @@
+use clap::ValueEnum;
+
+/// Which receipts are sent back automatically for incoming messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReceiptPolicy {
+    /// Never acknowledge messages.
+    Never,
+    /// Acknowledge that messages were delivered.
+    Delivery,
+    /// Acknowledge that messages were delivered and mark them read right away.
+    DeliveryAndRead,
+}
+
+impl ReceiptPolicy {
+    pub fn delivery(self) -> bool {
+        self != Self::Never
+    }
+
+    pub fn read(self) -> bool {
+        self == Self::DeliveryAndRead
+    }
+}
@@ -1,17 +1,232 @@
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
+    extract::{
+        multipart::{MultipartError, MultipartRejection},
+        rejection::JsonRejection,
+        Multipart, Path, State,
+    },
     Json,
 };
+use chrono::{DateTime, Utc};
 use hyper::{HeaderMap, StatusCode};
+use presage::prelude::Uuid;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::{error::TrySendError, Permit};
+use tracing::{debug, warn};
+use url::Url;
 use utoipa::ToSchema;
 
+use crate::command::{self, Command};
+use crate::destination::{parse_group_key, Destination};
+use crate::outbox::{Mention, Outbox, Outgoing, QuoteTarget};
+use crate::problem::Problem;
+use crate::scheduling_api::SendTime;
+use crate::signal_service::Queue;
+use crate::text;
+
+/// Longest message body accepted, in bytes.
+///
+/// Signal clients send anything longer as a long-text attachment instead.
+pub const MAX_CONTENT_LENGTH: usize = 2000;
+
+/// Most attachments accepted on a single message.
+pub const MAX_ATTACHMENTS: usize = 32;
+
+/// Content type of attachments nothing more specific is known about.
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Seconds a client is asked to wait when the queue is full.
+const QUEUE_FULL_RETRY_AFTER: u64 = 5;
+
+/// Largest multipart request accepted, in bytes.
+pub const MAX_MULTIPART_SIZE: usize = 100 * 1024 * 1024;
+
 /// A message to send.
 #[derive(Serialize, Deserialize, ToSchema, Clone)]
 pub struct Message {
+    /// The text to send. `@{uuid}` tokens are turned into mentions of that contact.
     content: String,
+    /// An earlier message this one replies to.
+    #[serde(default)]
+    quote: Option<Quote>,
+    /// Mentions of contacts, each covering a U+FFFC placeholder in the content.
+    #[serde(default)]
+    mentions: Vec<MentionRequest>,
+    /// How the content is formatted.
+    #[serde(default)]
+    format: Format,
+    /// Seconds after which the message disappears once read, instead of the thread's
+    /// disappearing-messages timer.
+    ///
+    /// In chats with a contact, Signal clients take the timer of a message as the timer of
+    /// the chat, so this sets the thread's timer for the messages after it too. In groups
+    /// it only applies to this message.
+    #[serde(default)]
+    expire_timer: Option<u32>,
+    /// HTTP(S) URL every change of the delivery status is POSTed to.
+    #[serde(default)]
+    status_callback: Option<String>,
+    /// When to send the message, if not right away.
+    #[serde(flatten)]
+    send_time: SendTime,
+}
+
+/// Markup of message content.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Sent as is.
+    #[default]
+    Plain,
+    /// `**bold**`, `*italic*`, `~~strikethrough~~`, `` `monospace` `` and `||spoiler||`
+    /// are turned into Signal text styles.
+    Markdown,
+}
+
+/// A mention of a contact at a placeholder in the message content.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct MentionRequest {
+    /// UUID of the mentioned contact.
+    uuid: String,
+    /// Offset of the U+FFFC placeholder in the content, in UTF-16 code units, after any
+    /// `@{uuid}` tokens are replaced and Markdown is stripped.
+    start: u32,
+}
+
+/// Reference to an earlier message in the same thread.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct Quote {
+    /// UUID of the author of the quoted message.
+    author: String,
+    /// The timestamp the quoted message was sent with.
+    timestamp: u64,
+}
+
+impl Message {
+    /// Checks the message, turning it into what is stored in the outbox.
+    pub(crate) fn validate(self) -> Result<Outgoing, Problem> {
+        if self.content.trim().is_empty() {
+            return Err(Problem::unprocessable(
+                "empty-content",
+                "message content must not be empty",
+            ));
+        }
+        if self.content.len() > MAX_CONTENT_LENGTH {
+            return Err(Problem::unprocessable(
+                "content-too-long",
+                format!("message content must be at most {MAX_CONTENT_LENGTH} bytes"),
+            ));
+        }
+        let quote = self.quote.map(Quote::validate).transpose()?;
+
+        let text::Rendered {
+            text: content,
+            mut mentions,
+            styles,
+        } = text::render(&self.content, self.format == Format::Markdown);
+        for mention in self.mentions {
+            let uuid = Uuid::parse_str(&mention.uuid).map_err(|e| {
+                Problem::unprocessable(
+                    "invalid-mention",
+                    format!("mentioned {:?} is not a UUID: {e}", mention.uuid),
+                )
+            })?;
+            let mention = Mention {
+                uuid,
+                start: mention.start,
+                length: 1,
+            };
+            text::check_mention(&content, &mention)
+                .map_err(|e| Problem::unprocessable("invalid-mention", e))?;
+            mentions.push(mention);
+        }
+
+        if self.expire_timer == Some(0) {
+            return Err(Problem::unprocessable(
+                "invalid-expire-timer",
+                "expire timer must be at least one second",
+            ));
+        }
+
+        Ok(Outgoing {
+            body: Some(content),
+            quote,
+            mentions,
+            styles,
+            expire_timer: self.expire_timer,
+            status_callback: self
+                .status_callback
+                .as_deref()
+                .map(parse_callback)
+                .transpose()?,
+            send_at: self.send_time.resolve()?,
+            ..Default::default()
+        })
+    }
+}
+
+fn parse_callback(url: &str) -> Result<Url, Problem> {
+    Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or_else(|| {
+            Problem::unprocessable(
+                "invalid-status-callback",
+                format!("status callback {url:?} is not an HTTP(S) URL"),
+            )
+        })
+}
+
+impl Quote {
+    fn validate(self) -> Result<QuoteTarget, Problem> {
+        let author = Uuid::parse_str(&self.author).map_err(|e| {
+            Problem::unprocessable(
+                "invalid-quote",
+                format!("quote author {:?} is not a UUID: {e}", self.author),
+            )
+        })?;
+
+        Ok(QuoteTarget {
+            author,
+            timestamp: self.timestamp,
+        })
+    }
+}
+
+/// A message with attachments, sent as `multipart/form-data`.
+///
+/// Each `file` part is one attachment. Its filename and content type are taken from the
+/// part headers, with the content type guessed from the filename when missing.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct MultipartMessage {
+    /// Text sent along with the attachments.
+    caption: Option<String>,
+    /// HTTP(S) URL every change of the delivery status is POSTed to.
+    status_callback: Option<String>,
+    /// RFC 3339 time to send the message at.
+    #[schema(format = DateTime)]
+    send_at: Option<String>,
+    /// Seconds to wait before sending the message.
+    delay_seconds: Option<u64>,
+    /// The files to attach, repeated once per file.
+    #[schema(value_type = Vec<String>, format = Binary)]
+    file: Vec<Vec<u8>>,
+}
+
+/// A message accepted for delivery.
+///
+/// The timestamp identifying the message in the thread is only assigned once it is sent,
+/// and can then be looked up with `GET /messages/{id}`.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct Accepted {
+    /// Outbox id of the message.
+    id: u64,
+}
+
+fn parse_destination(destination: &str) -> Result<Destination, Problem> {
+    destination
+        .parse()
+        .map_err(|e| Problem::bad_request("invalid-destination", e))
 }
 
 /// Send a message to a destination.
@@ -20,11 +235,14 @@ pub struct Message {
     path = "/message/{destination}",
     request_body = Message,
     responses(
-        (status = 200, description = "Message sent successfully"),
-        (status = 500, description = "Internal server error")
+        (status = 202, description = "Message stored and queued for delivery", body = Accepted),
+        (status = 400, description = "Malformed destination or request body", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Message content is empty or too long", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Message could not be stored", body = Problem, content_type = "application/problem+json")
     ),
     params(
-        ("destination" = String, Path, description = "The UUID of the destination")
+        ("destination" = String, Path, description = "The UUID or E.164 phone number of the destination")
     ),
     security(
         (), // <-- make optional authentication
@@ -33,15 +251,351 @@ pub struct Message {
 )]
 pub async fn send(
     Path(destination): Path<String>,
-    State(session): State<UnboundedSender<(String, String)>>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
     _headers: HeaderMap,
-    Json(message): Json<Message>,
-) -> impl IntoResponse {
+    message: Result<Json<Message>, JsonRejection>,
+) -> Result<(StatusCode, Json<Accepted>), Problem> {
+    let destination = parse_destination(&destination)?;
+    let Json(message) = message?;
+    let message = message.validate()?;
+
+    enqueue(&session, &outbox, destination, message).await
+}
+
+/// Send a message with attachments to a destination.
+#[utoipa::path(
+    post,
+    path = "/message/{destination}/multipart",
+    request_body(content = MultipartMessage, content_type = "multipart/form-data"),
+    responses(
+        (status = 202, description = "Message stored and queued for delivery", body = Accepted),
+        (status = 400, description = "Malformed destination or request body", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "No attachment, too many attachments, caption too long or unknown field", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Message could not be stored", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("destination" = String, Path, description = "The UUID or E.164 phone number of the destination")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn send_multipart(
+    Path(destination): Path<String>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<(StatusCode, Json<Accepted>), Problem> {
+    let destination = parse_destination(&destination)?;
+    let message = read_multipart(&outbox, multipart?, None).await?;
+    let attachments = message.attachments.clone();
+
+    enqueue(&session, &outbox, destination, message)
+        .await
+        .map_err(|problem| {
+            discard(&outbox, &attachments);
+            problem
+        })
+}
+
+/// Send a message to a group.
+#[utoipa::path(
+    post,
+    path = "/groups/{group_id}/messages",
+    request_body = Message,
+    responses(
+        (status = 202, description = "Message stored and queued for delivery", body = Accepted),
+        (status = 400, description = "Malformed group id or request body", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Message content is empty or too long", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Message could not be stored", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("group_id" = String, Path, description = "The group master key or group identifier, in base64 or hex")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn send_to_group(
+    Path(group_id): Path<String>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+    message: Result<Json<Message>, JsonRejection>,
+) -> Result<(StatusCode, Json<Accepted>), Problem> {
+    let key = parse_group_key(&group_id)
+        .map_err(|e| Problem::bad_request("invalid-group-id", e))?;
+    let Json(message) = message?;
+    let message = message.validate()?;
+
+    enqueue(&session, &outbox, Destination::Group(key), message).await
+}
+
+/// Send a message with attachments to a group.
+#[utoipa::path(
+    post,
+    path = "/groups/{group_id}/messages/multipart",
+    request_body(content = MultipartMessage, content_type = "multipart/form-data"),
+    responses(
+        (status = 202, description = "Message stored and queued for delivery", body = Accepted),
+        (status = 400, description = "Malformed group id or request body", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "No attachment, too many attachments, caption too long or unknown field", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Message could not be stored", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("group_id" = String, Path, description = "The group master key or group identifier, in base64 or hex")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn send_multipart_to_group(
+    Path(group_id): Path<String>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<(StatusCode, Json<Accepted>), Problem> {
+    let key = parse_group_key(&group_id)
+        .map_err(|e| Problem::bad_request("invalid-group-id", e))?;
+    let message = read_multipart(&outbox, multipart?, None).await?;
+    let attachments = message.attachments.clone();
+
+    enqueue(&session, &outbox, Destination::Group(key), message)
+        .await
+        .map_err(|problem| {
+            discard(&outbox, &attachments);
+            problem
+        })
+}
+
+/// Reads the caption and files of a multipart message, storing the files in the outbox.
+///
+/// `recipient` parts are collected into `recipients` when given, and turned away like
+/// any other unknown part otherwise. Files already stored are deleted again when the
+/// message is turned away.
+pub(crate) async fn read_multipart(
+    outbox: &Outbox,
+    multipart: Multipart,
+    recipients: Option<&mut Vec<String>>,
+) -> Result<Outgoing, Problem> {
+    let mut message = Outgoing::default();
+    match read_fields(outbox, multipart, recipients, &mut message).await {
+        Ok(()) => Ok(message),
+        Err(problem) => {
+            discard(outbox, &message.attachments);
+            Err(problem)
+        }
+    }
+}
+
+/// Deletes the attachments stored for a message that was turned away.
+pub(crate) fn discard(outbox: &Outbox, attachments: &[u64]) {
+    if let Err(e) = outbox.discard_attachments(attachments.iter().copied()) {
+        warn!("failed to delete the attachments of a rejected message: {e}");
+    }
+}
+
+async fn read_fields(
+    outbox: &Outbox,
+    mut multipart: Multipart,
+    mut recipients: Option<&mut Vec<String>>,
+    message: &mut Outgoing,
+) -> Result<(), Problem> {
+    let malformed = |e: MultipartError| Problem::bad_request("malformed-multipart", e.to_string());
+    let mut send_time = SendTime::default();
+
+    while let Some(field) = multipart.next_field().await.map_err(malformed)? {
+        if field.name() == Some("caption") {
+            let caption = field.text().await.map_err(malformed)?;
+            if caption.len() > MAX_CONTENT_LENGTH {
+                return Err(Problem::unprocessable(
+                    "content-too-long",
+                    format!("caption must be at most {MAX_CONTENT_LENGTH} bytes"),
+                ));
+            }
+            message.body = Some(caption).filter(|c| !c.trim().is_empty());
+            continue;
+        }
+        if field.name() == Some("status_callback") {
+            let url = field.text().await.map_err(malformed)?;
+            message.status_callback = Some(parse_callback(&url)?);
+            continue;
+        }
+        if field.name() == Some("send_at") {
+            let at = field.text().await.map_err(malformed)?;
+            let at = DateTime::parse_from_rfc3339(&at).map_err(|e| {
+                Problem::unprocessable("invalid-send-time", format!("send_at {at:?}: {e}"))
+            })?;
+            send_time.send_at = Some(at.with_timezone(&Utc));
+            continue;
+        }
+        if field.name() == Some("delay_seconds") {
+            let delay = field.text().await.map_err(malformed)?;
+            let delay = delay.trim().parse().map_err(|e| {
+                Problem::unprocessable("invalid-send-time", format!("delay_seconds {delay:?}: {e}"))
+            })?;
+            send_time.delay_seconds = Some(delay);
+            continue;
+        }
+        if let (Some("recipient"), Some(recipients)) = (field.name(), recipients.as_deref_mut()) {
+            recipients.push(field.text().await.map_err(malformed)?);
+            continue;
+        }
+        if field.name() != Some("file") {
+            return Err(Problem::unprocessable(
+                "unknown-field",
+                format!("unknown multipart field {:?}", field.name().unwrap_or_default()),
+            ));
+        }
+
+        if message.attachments.len() == MAX_ATTACHMENTS {
+            return Err(Problem::unprocessable(
+                "too-many-attachments",
+                format!("at most {MAX_ATTACHMENTS} attachments can be sent at once"),
+            ));
+        }
+
+        let file_name = field.file_name().map(str::to_owned);
+        let content_type = field
+            .content_type()
+            .filter(|c| *c != OCTET_STREAM)
+            .map(str::to_owned)
+            .or_else(|| {
+                file_name
+                    .as_deref()
+                    .and_then(|name| mime_guess::from_path(name).first_raw())
+                    .map(str::to_owned)
+            })
+            .unwrap_or_else(|| OCTET_STREAM.to_owned());
+        let data = field.bytes().await.map_err(malformed)?;
+
+        let attachment = outbox
+            .store_attachment(content_type, file_name, &data)
+            .map_err(|e| Problem::internal(format!("failed to store attachment: {e}")))?;
+        message.attachments.push(attachment.id);
+    }
+
+    if message.attachments.is_empty() {
+        return Err(Problem::unprocessable(
+            "missing-attachment",
+            "a multipart message needs at least one file",
+        ));
+    }
+    message.send_at = send_time.resolve()?;
+    Ok(())
+}
+
+/// Stores a validated message in the outbox and asks the service to deliver it.
+pub(crate) async fn enqueue(
+    session: &Queue,
+    outbox: &Outbox,
+    destination: Destination,
+    message: Outgoing,
+) -> Result<(StatusCode, Json<Accepted>), Problem> {
+    let accepted = accept(session, outbox, destination, message).await?;
+    Ok((StatusCode::ACCEPTED, Json(accepted)))
+}
+
+/// Like [`enqueue`], for callers answering with more than one accepted message.
+pub(crate) async fn accept(
+    session: &Queue,
+    outbox: &Outbox,
+    destination: Destination,
+    message: Outgoing,
+) -> Result<Accepted, Problem> {
+    let permit = reserve(session)?;
+    let entry = outbox
+        .push(destination, message)
+        .await
+        .map_err(|e| Problem::internal(format!("failed to store message: {e}")))?;
+
+    debug!("queued message {} for {}", entry.id, entry.destination);
+
+    // The entry is durable now, so a stopped service picks it up again on restart.
+    match permit {
+        Some(permit) => permit.send(Command::Deliver(entry.id)),
+        None => warn!("signal service is not running, message {} stays queued", entry.id),
+    }
+
+    Ok(Accepted { id: entry.id })
+}
+
+/// Reserves a place in the queue, or returns `None` if the service is not running.
+///
+/// Fails with `429 Too Many Requests` when the queue is full, before anything is stored.
+pub(crate) fn reserve(session: &Queue) -> Result<Option<Permit<'_, Command>>, Problem> {
+    match session.try_reserve() {
+        Ok(permit) => Ok(Some(permit)),
+        Err(TrySendError::Full(())) => Err(no_room(session)),
+        Err(TrySendError::Closed(())) => Ok(None),
+    }
+}
 
-    println!("received message: {}", message.content);
+pub(crate) fn queue_full(detail: impl Into<String>) -> Problem {
+    Problem::new(StatusCode::TOO_MANY_REQUESTS, "queue-full", "Queue full")
+        .with_detail(detail)
+        .with_retry_after(QUEUE_FULL_RETRY_AFTER)
+}
+
+fn no_room(session: &Queue) -> Problem {
+    queue_full(format!(
+        "all {} places in the queue are taken",
+        session.max_capacity()
+    ))
+}
 
-    match session.send((destination, message.content)) {
-        Ok(_) => StatusCode::OK,
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+/// Sends a command the service answers right away and waits for its outcome.
+pub(crate) async fn dispatch<T>(
+    session: &Queue,
+    (command, outcome): (Command, command::Outcome<T>),
+) -> Result<T, Problem> {
+    match session.try_send(command) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => return Err(no_room(session)),
+        Err(TrySendError::Closed(_)) => {
+            return Err(Problem::unavailable("signal service is not running"))
+        }
     }
-}
\ No newline at end of file
+
+    match outcome.await {
+        Ok(result) => result.map_err(Problem::from),
+        Err(_) => Err(Problem::unavailable(
+            "signal service stopped before answering",
+        )),
+    }
+}
+
+/// How full the queue of commands for the service is.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct QueueStatus {
+    /// Commands and messages waiting for the service. Messages scheduled for later or
+    /// waiting for a retry only count once they are due.
+    depth: usize,
+    /// Commands and messages the queue holds before requests are turned away with `429`.
+    capacity: usize,
+}
+
+/// Get how full the queue is, to throttle before requests are turned away.
+#[utoipa::path(
+    get,
+    path = "/queue",
+    responses(
+        (status = 200, description = "Depth and capacity of the queue", body = QueueStatus)
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn queue_status(State(session): State<Queue>) -> Json<QueueStatus> {
+    Json(QueueStatus {
+        depth: session.depth(),
+        capacity: session.max_capacity(),
+    })
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use presage::prelude::phonenumber::PhoneNumber;
+use presage::prelude::Uuid;
+use presage::Store;
+
+/// Maps phone numbers to the ACI of the Signal account registered with them.
+///
+/// Implemented by the contact store lookup used in production, and by a fake in the tests
+/// below.
+pub trait Resolver {
+    /// Returns the ACI for `number`, or `None` when no account is known for it.
+    fn resolve<'a>(&'a self, number: &'a PhoneNumber)
+        -> LocalBoxFuture<'a, anyhow::Result<Option<Uuid>>>;
+}
+
+/// Resolves numbers through the contacts synced to the local store, falling back to an
+/// optional lookup for numbers that are not a contact.
+///
+/// The relayer does not configure a lookup yet, so only numbers of contacts resolve.
+pub struct ContactStoreResolver<C: Store> {
+    store: C,
+    lookup: Option<Box<dyn Resolver>>,
+}
+
+impl<C: Store> ContactStoreResolver<C> {
+    pub fn new(store: C, lookup: Option<Box<dyn Resolver>>) -> Self {
+        Self { store, lookup }
+    }
+
+    fn find_contact(&self, number: &PhoneNumber) -> anyhow::Result<Option<Uuid>> {
+        for contact in self.store.contacts()? {
+            let contact = contact?;
+            if contact.phone_number.as_ref() == Some(number) {
+                return Ok(Some(contact.uuid));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<C: Store> Resolver for ContactStoreResolver<C> {
+    fn resolve<'a>(
+        &'a self,
+        number: &'a PhoneNumber,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Option<Uuid>>> {
+        async move {
+            if let Some(uuid) = self.find_contact(number)? {
+                return Ok(Some(uuid));
+            }
+            match &self.lookup {
+                Some(lookup) => lookup.resolve(number).await,
+                None => Ok(None),
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Remembers the numbers another [`Resolver`] found an account for.
+pub struct CachingResolver<R> {
+    inner: R,
+    cache: Mutex<HashMap<PhoneNumber, Uuid>>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            cache: Default::default(),
+        }
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve<'a>(
+        &'a self,
+        number: &'a PhoneNumber,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Option<Uuid>>> {
+        async move {
+            if let Some(uuid) = self.cache.lock().unwrap().get(number) {
+                return Ok(Some(*uuid));
+            }
+
+            let resolved = self.inner.resolve(number).await?;
+            if let Some(uuid) = resolved {
+                self.cache.lock().unwrap().insert(number.clone(), uuid);
+            }
+            Ok(resolved)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use futures::executor::block_on;
+    use presage::prelude::phonenumber;
+    use presage_store_sled::{MigrationConflictStrategy, SledStore};
+
+    use super::*;
+
+    /// Knows a fixed set of numbers, and counts how often it is asked.
+    #[derive(Default)]
+    struct FakeResolver {
+        known: HashMap<PhoneNumber, Uuid>,
+        calls: Cell<usize>,
+    }
+
+    impl Resolver for FakeResolver {
+        fn resolve<'a>(
+            &'a self,
+            number: &'a PhoneNumber,
+        ) -> LocalBoxFuture<'a, anyhow::Result<Option<Uuid>>> {
+            self.calls.set(self.calls.get() + 1);
+            let resolved = self.known.get(number).copied();
+            async move { Ok(resolved) }.boxed_local()
+        }
+    }
+
+    fn number(s: &str) -> PhoneNumber {
+        phonenumber::parse(None, s).unwrap()
+    }
+
+    #[test]
+    fn caches_resolved_numbers() {
+        let known = number("+41446681800");
+        let uuid = Uuid::from_u128(1);
+        let resolver = CachingResolver::new(FakeResolver {
+            known: HashMap::from([(known.clone(), uuid)]),
+            ..Default::default()
+        });
+
+        assert_eq!(block_on(resolver.resolve(&known)).unwrap(), Some(uuid));
+        assert_eq!(block_on(resolver.resolve(&known)).unwrap(), Some(uuid));
+        assert_eq!(resolver.inner.calls.get(), 1);
+    }
+
+    #[test]
+    fn does_not_cache_unknown_numbers() {
+        let unknown = number("+41446681801");
+        let resolver = CachingResolver::new(FakeResolver::default());
+
+        assert_eq!(block_on(resolver.resolve(&unknown)).unwrap(), None);
+        assert_eq!(block_on(resolver.resolve(&unknown)).unwrap(), None);
+        assert_eq!(resolver.inner.calls.get(), 2);
+    }
+
+    #[test]
+    fn falls_back_to_lookup_for_numbers_that_are_not_contacts() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledStore::open_with_passphrase(
+            dir.path(),
+            None::<String>,
+            MigrationConflictStrategy::Raise,
+        )
+        .unwrap();
+        let known = number("+41446681800");
+        let uuid = Uuid::from_u128(1);
+        let lookup = FakeResolver {
+            known: HashMap::from([(known.clone(), uuid)]),
+            ..Default::default()
+        };
+
+        let resolver = ContactStoreResolver::new(store.clone(), Some(Box::new(lookup)));
+        assert_eq!(block_on(resolver.resolve(&known)).unwrap(), Some(uuid));
+        assert_eq!(
+            block_on(resolver.resolve(&number("+41446681801"))).unwrap(),
+            None
+        );
+
+        let resolver = ContactStoreResolver::new(store, None);
+        assert_eq!(block_on(resolver.resolve(&known)).unwrap(), None);
+    }
+}
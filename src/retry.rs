@@ -0,0 +1,200 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+use rand::Rng;
+
+use crate::command::SendError;
+
+/// Kinds of [`SendError`] that a [`RetryPolicy`] can decide to retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ErrorClass {
+    UnknownRecipient,
+    RateLimited,
+    UntrustedIdentity,
+    Network,
+    Other,
+}
+
+impl From<&SendError> for ErrorClass {
+    fn from(e: &SendError) -> Self {
+        match e {
+            SendError::UnknownRecipient => Self::UnknownRecipient,
+            // A challenge pauses sending rather than being retried, but it is a rate limit.
+            SendError::RateLimited | SendError::ProofRequired { .. } => Self::RateLimited,
+            SendError::UntrustedIdentity => Self::UntrustedIdentity,
+            SendError::Network(_) => Self::Network,
+            SendError::Other(_) => Self::Other,
+        }
+    }
+}
+
+#[derive(clap::Parser, Debug, Clone)]
+#[group(skip)]
+pub struct RetryArguments {
+    #[clap(
+        long,
+        env,
+        default_value = "5",
+        help = "Number of attempts before a message is dead-lettered"
+    )]
+    pub retry_max_attempts: u32,
+
+    #[clap(
+        long,
+        env,
+        default_value = "1000",
+        help = "Delay before the first retry, in milliseconds"
+    )]
+    pub retry_initial_backoff_ms: u64,
+
+    #[clap(
+        long,
+        env,
+        default_value = "300000",
+        help = "Upper bound for the delay between retries, in milliseconds"
+    )]
+    pub retry_max_backoff_ms: u64,
+
+    #[clap(
+        long,
+        env,
+        default_value = "0.2",
+        value_parser = parse_jitter,
+        help = "Fraction of each delay that is randomised, between 0 and 1"
+    )]
+    pub retry_jitter: f64,
+
+    #[clap(
+        long,
+        env,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "rate-limited,network",
+        help = "Errors that are retried, any other error dead-letters the message"
+    )]
+    pub retry_on: Vec<ErrorClass>,
+}
+
+/// Parses a jitter fraction, which must be a number between 0 and 1.
+fn parse_jitter(s: &str) -> Result<f64, String> {
+    let jitter: f64 = s.parse().map_err(|e| format!("{s:?}: {e}"))?;
+    // NaN fails the range check as well.
+    if !(0.0..=1.0).contains(&jitter) {
+        return Err(format!("{s:?} is not between 0 and 1"));
+    }
+    Ok(jitter)
+}
+
+/// Decides whether and when a failed send is attempted again.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub jitter: f64,
+    pub retry_on: Vec<ErrorClass>,
+}
+
+impl From<RetryArguments> for RetryPolicy {
+    fn from(args: RetryArguments) -> Self {
+        Self {
+            max_attempts: args.retry_max_attempts,
+            initial_backoff: Duration::from_millis(args.retry_initial_backoff_ms),
+            max_backoff: Duration::from_millis(args.retry_max_backoff_ms),
+            jitter: args.retry_jitter,
+            retry_on: args.retry_on,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait before the next attempt, or `None` to give up.
+    ///
+    /// `attempts` is the number of attempts made so far, including the failed one.
+    pub fn next_attempt(&self, attempts: u32, error: &SendError) -> Option<Duration> {
+        if attempts >= self.max_attempts || !self.retry_on.contains(&error.into()) {
+            return None;
+        }
+
+        Some(self.backoff(attempts))
+    }
+
+    /// Exponential backoff, doubling per attempt up to `max_backoff`, with jitter applied.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(31);
+        let delay = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter..=1.0 + self.jitter);
+        delay.mul_f64(factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            jitter,
+            retry_on: vec![ErrorClass::RateLimited, ErrorClass::Network],
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = policy(0.0);
+        for (attempts, secs) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 10), (40, 10)] {
+            assert_eq!(
+                policy.backoff(attempts),
+                Duration::from_secs(secs),
+                "{attempts}"
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_stays_within_the_jitter() {
+        let policy = policy(0.5);
+        for _ in 0..100 {
+            let delay = policy.backoff(3);
+            assert!(
+                delay >= Duration::from_secs(2) && delay <= Duration::from_secs(6),
+                "{delay:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts_or_on_other_errors() {
+        let policy = policy(0.0);
+        assert_eq!(
+            policy.next_attempt(1, &SendError::RateLimited),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            policy.next_attempt(4, &SendError::Network("reset".into())),
+            Some(Duration::from_secs(8))
+        );
+        assert_eq!(policy.next_attempt(5, &SendError::RateLimited), None);
+        assert_eq!(policy.next_attempt(1, &SendError::UnknownRecipient), None);
+    }
+
+    #[test]
+    fn jitter_must_be_a_fraction() {
+        assert_eq!(parse_jitter("0"), Ok(0.0));
+        assert_eq!(parse_jitter("0.2"), Ok(0.2));
+        assert_eq!(parse_jitter("1"), Ok(1.0));
+        for s in ["-0.1", "1.5", "NaN", "inf", "a lot"] {
+            assert!(parse_jitter(s).is_err(), "{s}");
+        }
+    }
+}
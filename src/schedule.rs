@@ -0,0 +1,34 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use chrono::{DateTime, Utc};
+use tokio::time::sleep;
+
+/// Outbox entries waiting for a point in time, soonest first.
+#[derive(Default)]
+pub struct Schedule {
+    heap: BinaryHeap<Reverse<(DateTime<Utc>, u64)>>,
+}
+
+impl Schedule {
+    pub fn push(&mut self, at: DateTime<Utc>, id: u64) {
+        self.heap.push(Reverse((at, id)));
+    }
+
+    /// Waits until the earliest entry is due and returns its id.
+    ///
+    /// Never completes while the schedule is empty. Cancel safe: nothing is removed
+    /// before the wait is over.
+    pub async fn next(&mut self) -> u64 {
+        let Some(&Reverse((at, id))) = self.heap.peek() else {
+            return std::future::pending().await;
+        };
+
+        if let Ok(wait) = (at - Utc::now()).to_std() {
+            sleep(wait).await;
+        }
+        self.heap.pop();
+
+        id
+    }
+}
@@ -0,0 +1,173 @@
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::command::Command;
+use crate::outbox::{Entry, Outbox};
+use crate::problem::Problem;
+use crate::relayer::reserve;
+use crate::signal_service::Queue;
+
+/// Furthest in the future a message can be scheduled, in days.
+const MAX_SCHEDULE_DAYS: i64 = 365;
+
+/// When to send a message, at a given time or after a delay.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Default)]
+pub struct SendTime {
+    /// RFC 3339 time to send the message at. A time in the past sends it right away.
+    #[serde(default)]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub send_at: Option<DateTime<Utc>>,
+    /// Seconds to wait before sending the message.
+    #[serde(default)]
+    pub delay_seconds: Option<u64>,
+}
+
+impl SendTime {
+    /// Returns when to send the message, or `None` to send it right away.
+    pub(crate) fn resolve(&self) -> Result<Option<DateTime<Utc>>, Problem> {
+        let at = match (self.send_at, self.delay_seconds) {
+            (Some(_), Some(_)) => {
+                return Err(Problem::unprocessable(
+                    "invalid-send-time",
+                    "only one of send_at and delay_seconds can be given",
+                ))
+            }
+            (Some(at), None) => at,
+            (None, Some(delay)) => {
+                let delay = i64::try_from(delay).map_err(|_| too_far())?;
+                if delay > chrono::Duration::days(MAX_SCHEDULE_DAYS).num_seconds() {
+                    return Err(too_far());
+                }
+                Utc::now() + chrono::Duration::seconds(delay)
+            }
+            (None, None) => return Ok(None),
+        };
+
+        if at - Utc::now() > chrono::Duration::days(MAX_SCHEDULE_DAYS) {
+            return Err(too_far());
+        }
+        Ok(Some(at))
+    }
+}
+
+fn too_far() -> Problem {
+    Problem::unprocessable(
+        "invalid-send-time",
+        format!("messages can be scheduled at most {MAX_SCHEDULE_DAYS} days ahead"),
+    )
+}
+
+fn missing(id: u64) -> Problem {
+    Problem::not_found(
+        "unknown-scheduled-message",
+        format!("no scheduled message with id {id}"),
+    )
+}
+
+/// List messages waiting for the time they were scheduled for.
+#[utoipa::path(
+    get,
+    path = "/scheduled",
+    responses(
+        (status = 200, description = "Scheduled messages, soonest first", body = [Entry]),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn list_scheduled(State(outbox): State<Outbox>) -> Result<Json<Vec<Entry>>, Problem> {
+    outbox.scheduled().map(Json).map_err(Problem::storage)
+}
+
+/// Move a scheduled message to another time.
+///
+/// Only messages that were not attempted yet can be rescheduled.
+#[utoipa::path(
+    patch,
+    path = "/scheduled/{id}",
+    request_body = SendTime,
+    responses(
+        (status = 200, description = "The rescheduled message", body = Entry),
+        (status = 400, description = "Malformed request body", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No scheduled message with this id", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Missing or invalid send time", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("id" = u64, Path, description = "Outbox id of the message")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn reschedule(
+    Path(id): Path<u64>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+    time: Result<Json<SendTime>, JsonRejection>,
+) -> Result<Json<Entry>, Problem> {
+    let Json(time) = time?;
+    let at = time.resolve()?.ok_or_else(|| {
+        Problem::unprocessable(
+            "invalid-send-time",
+            "send_at or delay_seconds must be given",
+        )
+    })?;
+
+    let permit = reserve(&session)?;
+    let entry = outbox
+        .reschedule(id, at)
+        .map_err(Problem::storage)?
+        .ok_or_else(|| missing(id))?;
+    info!("rescheduled message {id} to {at}");
+
+    // The service keeps the old time too, and ignores it once it comes.
+    match permit {
+        Some(permit) => permit.send(Command::Deliver(id)),
+        None => warn!("signal service is not running, message {id} stays scheduled"),
+    }
+    Ok(Json(entry))
+}
+
+/// Cancel a scheduled message.
+///
+/// Only messages that were not attempted yet can be cancelled.
+#[utoipa::path(
+    delete,
+    path = "/scheduled/{id}",
+    responses(
+        (status = 204, description = "Scheduled message cancelled"),
+        (status = 404, description = "No scheduled message with this id", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("id" = u64, Path, description = "Outbox id of the message")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn cancel_scheduled(
+    Path(id): Path<u64>,
+    State(outbox): State<Outbox>,
+) -> Result<StatusCode, Problem> {
+    outbox
+        .cancel(id)
+        .map_err(Problem::storage)?
+        .ok_or_else(|| missing(id))?;
+    info!("cancelled scheduled message {id}");
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,12 +1,20 @@
 use std::net::{Ipv4Addr, SocketAddr};
 
-use axum::{routing, Router, Server};
+use axum::{
+    extract::{DefaultBodyLimit, FromRef},
+    middleware, routing, Router, Server,
+};
 
 use hyper::Error;
 use tower_http::{trace::{TraceLayer, DefaultOnRequest, DefaultMakeSpan, DefaultOnResponse}, LatencyUnit};
 use tracing::Level;
-use crate::relayer;
-use tokio::sync::mpsc;
+use crate::{
+    admin, broadcast, callback, command, idempotency, messages, outbox, problem, relayer,
+    scheduling_api, threads,
+};
+use crate::idempotency::IdempotencyKeys;
+use crate::outbox::Outbox;
+use crate::signal_service::Queue;
 use utoipa::{
     openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
     Modify, OpenApi,
@@ -16,18 +24,87 @@ use utoipa_rapidoc::RapiDoc;
 use utoipa_redoc::{Redoc, Servable};
 use utoipa_swagger_ui::SwaggerUi;
 
-pub async fn start(rx: mpsc::UnboundedSender<(String, String)>) -> Result<(), Error> {
+/// State shared by the request handlers.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub queue: Queue,
+    pub outbox: Outbox,
+}
+
+pub async fn start(
+    queue: Queue,
+    outbox: Outbox,
+    idempotency_keys: IdempotencyKeys,
+) -> Result<(), Error> {
     #[derive(OpenApi)]
     #[openapi(
         paths(
             relayer::send,
+            relayer::send_multipart,
+            relayer::send_to_group,
+            relayer::send_multipart_to_group,
+            relayer::queue_status,
+            broadcast::broadcast,
+            broadcast::broadcast_multipart,
+            threads::react,
+            threads::delete_message,
+            threads::edit_message,
+            threads::set_expiration,
+            threads::start_typing,
+            threads::stop_typing,
+            threads::mark_read,
+            messages::get_message,
+            scheduling_api::list_scheduled,
+            scheduling_api::reschedule,
+            scheduling_api::cancel_scheduled,
+            admin::list_dead_letters,
+            admin::get_dead_letter,
+            admin::replay_dead_letter,
+            admin::purge_dead_letter,
+            admin::purge_dead_letters,
+            admin::get_challenge,
+            admin::answer_challenge,
         ),
         components(
-            schemas(relayer::Message)
+            schemas(
+                relayer::Message,
+                relayer::Quote,
+                relayer::MentionRequest,
+                relayer::Format,
+                threads::Reaction,
+                threads::Expiration,
+                threads::ReadMessages,
+                relayer::MultipartMessage,
+                relayer::Accepted,
+                relayer::QueueStatus,
+                broadcast::BroadcastMessage,
+                broadcast::MultipartBroadcast,
+                broadcast::Broadcast,
+                broadcast::RecipientResult,
+                problem::Problem,
+                outbox::Entry,
+                outbox::Outgoing,
+                outbox::DeliveryStatus,
+                outbox::RecipientStatus,
+                messages::MessageStatus,
+                scheduling_api::SendTime,
+                callback::StatusChange,
+                outbox::QuoteTarget,
+                outbox::ReactionTarget,
+                outbox::Mention,
+                outbox::StyleRange,
+                outbox::TextStyle,
+                outbox::State,
+                command::SendError,
+                command::Challenge,
+                admin::ChallengeAnswer,
+                admin::Purged,
+            )
         ),
         modifiers(&SecurityAddon),
         tags(
-            (name = "signal", description = "Signal API")
+            (name = "signal", description = "Signal API"),
+            (name = "admin", description = "Outbox administration")
         )
     )]
     struct ApiDoc;
@@ -45,6 +122,8 @@ pub async fn start(rx: mpsc::UnboundedSender<(String, String)>) -> Result<(), Er
         }
     }
 
+    tokio::spawn(idempotency_keys.clone().purge_expired());
+
     let app = Router::new()
         .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
         .merge(Redoc::with_url("/redoc", ApiDoc::openapi()))
@@ -57,7 +136,86 @@ pub async fn start(rx: mpsc::UnboundedSender<(String, String)>) -> Result<(), Er
             "/message/:destination",
             routing::post(relayer::send),
         )
-        .with_state(rx)
+        .route(
+            "/message/:destination/multipart",
+            routing::post(relayer::send_multipart)
+                .layer(DefaultBodyLimit::max(relayer::MAX_MULTIPART_SIZE)),
+        )
+        .route(
+            "/groups/:group_id/messages",
+            routing::post(relayer::send_to_group),
+        )
+        .route(
+            "/groups/:group_id/messages/multipart",
+            routing::post(relayer::send_multipart_to_group)
+                .layer(DefaultBodyLimit::max(relayer::MAX_MULTIPART_SIZE)),
+        )
+        .route(
+            "/queue",
+            routing::get(relayer::queue_status),
+        )
+        .route(
+            "/broadcast",
+            routing::post(broadcast::broadcast),
+        )
+        .route(
+            "/broadcast/multipart",
+            routing::post(broadcast::broadcast_multipart)
+                .layer(DefaultBodyLimit::max(relayer::MAX_MULTIPART_SIZE)),
+        )
+        .route(
+            "/messages/:id",
+            routing::get(messages::get_message),
+        )
+        .route(
+            "/threads/:thread/reactions",
+            routing::post(threads::react),
+        )
+        .route(
+            "/threads/:thread/expiration",
+            routing::put(threads::set_expiration),
+        )
+        .route(
+            "/threads/:thread/typing",
+            routing::put(threads::start_typing).delete(threads::stop_typing),
+        )
+        .route(
+            "/threads/:thread/read",
+            routing::post(threads::mark_read),
+        )
+        .route(
+            "/threads/:thread/messages/:timestamp",
+            routing::delete(threads::delete_message).patch(threads::edit_message),
+        )
+        .route(
+            "/scheduled",
+            routing::get(scheduling_api::list_scheduled),
+        )
+        .route(
+            "/scheduled/:id",
+            routing::patch(scheduling_api::reschedule).delete(scheduling_api::cancel_scheduled),
+        )
+        .route(
+            "/admin/dead-letters",
+            routing::get(admin::list_dead_letters).delete(admin::purge_dead_letters),
+        )
+        .route(
+            "/admin/dead-letters/:id",
+            routing::get(admin::get_dead_letter).delete(admin::purge_dead_letter),
+        )
+        .route(
+            "/admin/dead-letters/:id/replay",
+            routing::post(admin::replay_dead_letter),
+        )
+        .route(
+            "/admin/challenge",
+            routing::get(admin::get_challenge).post(admin::answer_challenge),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            idempotency_keys,
+            idempotency::idempotent,
+        ))
+        .with_state(AppState { queue, outbox })
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(
@@ -1,80 +1,808 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::Path;
-use std::time::UNIX_EPOCH;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use std::time::Duration;
 use anyhow::Context;
-use chrono::Local;
+use chrono::{Local, Utc};
 use futures::{pin_mut, StreamExt};
 use notify_rust::Notification;
 use presage::prelude::content::Reaction;
-use presage::prelude::proto::data_message::Quote;
+use presage::prelude::proto::data_message::{Delete, Flags, Quote};
 use presage::prelude::proto::sync_message::Sent;
 use presage::{Store, Thread};
-use presage::prelude::{Content, SyncMessage};
+use presage::prelude::{Content, Group, GroupMasterKey, GroupSecretParams, SyncMessage};
+use presage::prelude::phonenumber::PhoneNumber;
+use presage::prelude::proto::body_range::{AssociatedValue, Style};
+use presage::prelude::proto::receipt_message::Type;
+use presage::prelude::proto::typing_message::Action;
+use presage::prelude::proto::{
+    AttachmentPointer, BodyRange, EditMessage, GroupContextV2, ReceiptMessage, TypingMessage,
+};
+use presage::prelude::AttachmentSpec;
+use prost::Message as _;
 use presage::{Registered, Manager, prelude::{ContentBody, DataMessage, Uuid}};
 use tempfile::Builder;
 use tokio::fs;
-use tokio::{sync::mpsc, task, time::sleep};
-use tracing::{error, info, warn};
+use tokio::sync::mpsc::{self, error::TrySendError, Permit};
+use tokio::sync::Mutex;
+use tokio::{task, time::{sleep, Instant}};
+use tracing::{debug, error, info, warn};
 
-pub type Queue = mpsc::UnboundedSender<(String, String)>;
-pub type QueueReceiver = mpsc::UnboundedReceiver<(String, String)>;
+use crate::callback::{Callbacks, StatusChange};
+use crate::command::{self, Challenge, Command, SendError};
+use crate::destination::{Destination, GROUP_KEY_LENGTH};
+use crate::lanes::Lanes;
+use crate::outbox::{
+    DeliveryStatus, Entry, Outbox, Outcome, Outgoing, QuoteTarget, State, TextStyle,
+};
+use crate::ratelimit::RateLimiter;
+use crate::receipts::ReceiptPolicy;
+use crate::resolver::Resolver;
+use crate::retry::RetryPolicy;
+use crate::schedule::Schedule;
+
+/// Creates the queue of commands for the service, which turns requests away once
+/// `capacity` commands and outbox entries are waiting for the service.
+pub fn queue(capacity: usize) -> (Queue, QueueReceiver) {
+    let (commands, receiver) = mpsc::channel(capacity);
+    let backlog = Arc::new(AtomicUsize::new(0));
+    let queue = Queue {
+        commands,
+        backlog: backlog.clone(),
+    };
+    let receiver = QueueReceiver {
+        commands: receiver,
+        backlog,
+    };
+
+    (queue, receiver)
+}
+
+/// Sends commands to the service.
+///
+/// The service takes commands right away, so due outbox entries it has yet to send count
+/// against the capacity too. Otherwise requests would only be turned away while the
+/// service is busy, not while it is behind.
+#[derive(Clone)]
+pub struct Queue {
+    commands: mpsc::Sender<Command>,
+    /// Outbox entries the service has yet to send.
+    backlog: Arc<AtomicUsize>,
+}
+
+impl Queue {
+    /// Reserves a place for a command, failing as full while the backlog takes up the
+    /// whole capacity.
+    pub fn try_reserve(&self) -> Result<Permit<'_, Command>, TrySendError<()>> {
+        if !self.commands.is_closed() && self.capacity() == 0 {
+            return Err(TrySendError::Full(()));
+        }
+        self.commands.try_reserve()
+    }
+
+    /// Sends a command the service answers right away, whatever the backlog.
+    pub fn try_send(&self, command: Command) -> Result<(), TrySendError<Command>> {
+        self.commands.try_send(command)
+    }
+
+    /// Commands and outbox entries waiting for the service.
+    pub fn depth(&self) -> usize {
+        let commands = self.commands.max_capacity() - self.commands.capacity();
+        commands + self.backlog.load(Ordering::Relaxed)
+    }
+
+    /// Places left for commands and outbox entries.
+    pub fn capacity(&self) -> usize {
+        self.max_capacity().saturating_sub(self.depth())
+    }
+
+    pub fn max_capacity(&self) -> usize {
+        self.commands.max_capacity()
+    }
+}
+
+/// Receives the commands sent through a [`Queue`], and tells it the backlog.
+pub struct QueueReceiver {
+    commands: mpsc::Receiver<Command>,
+    backlog: Arc<AtomicUsize>,
+}
+
+impl QueueReceiver {
+    async fn recv(&mut self) -> Option<Command> {
+        self.commands.recv().await
+    }
+
+    fn set_backlog(&self, backlog: usize) {
+        self.backlog.store(backlog, Ordering::Relaxed);
+    }
+}
+
+/// How long to wait before re-opening the message stream after it ends.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How often a refreshed typing indicator is sent again.
+///
+/// Clients hide the indicator after 15 seconds without an update.
+const TYPING_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How the service sends: when failed sends are retried, which receipts are sent for
+/// incoming messages, and how fast messages go out.
+pub struct Policies {
+    pub retry: RetryPolicy,
+    pub receipts: ReceiptPolicy,
+    pub rate_limiter: RateLimiter,
+}
 
 pub struct SignalServiceWrapper<C: Store + 'static> {
     queue: QueueReceiver,
     config_store: C,
+    outbox: Outbox,
+    receipts: ReceiptPolicy,
+    rate_limiter: RateLimiter,
+    callbacks: Callbacks,
+    worker: Worker<C>,
+    /// Outbox entries waiting for their next attempt.
+    schedule: Schedule,
+    /// Outbox entries due, by the thread they are sent to, waiting for or being sent by a
+    /// worker.
+    lanes: Lanes<Destination, u64>,
+    /// The lanes of the outbox entries in `lanes`, so they are not added twice and a
+    /// retried entry finds its lane again.
+    dispatched: HashMap<u64, Destination>,
+    /// Outbox entries due and neither sent nor given up on, wherever they wait: in a lane,
+    /// held back by a challenge or over a rate limit. Entries scheduled for later, or
+    /// waiting for their next attempt, are not counted until due.
+    backlog: HashSet<u64>,
+    reports: mpsc::UnboundedSender<Report>,
+    finished: mpsc::UnboundedReceiver<Report>,
+    updates: mpsc::UnboundedSender<Update>,
+    updated: mpsc::UnboundedReceiver<Update>,
+    /// Tasks keeping a typing indicator shown, by destination.
+    typing_refreshes: HashMap<Destination, task::JoinHandle<()>>,
+    /// Proof Signal asks for before sending more, while sending is paused.
+    challenge: Option<Challenge>,
+    /// Outbox entries held back until the challenge is answered, in the order accepted.
+    held: BTreeSet<u64>,
     // Put other persistent data here
 }
 
 impl<C: Store + 'static> SignalServiceWrapper<C> {
-    pub fn new(queue: QueueReceiver, config_store: C) -> Self {
+    pub fn new(
+        queue: QueueReceiver,
+        config_store: C,
+        outbox: Outbox,
+        policies: Policies,
+        resolver: Box<dyn Resolver>,
+        concurrency: usize,
+    ) -> Self {
+        let Policies {
+            retry,
+            receipts,
+            rate_limiter,
+        } = policies;
+        let callbacks = Callbacks::default();
+        let worker = Worker {
+            config_store: config_store.clone(),
+            outbox: outbox.clone(),
+            retry_policy: retry,
+            callbacks: callbacks.clone(),
+            resolver: resolver.into(),
+            uploads: Rc::default(),
+        };
+        let (reports, finished) = mpsc::unbounded_channel();
+        let (updates, updated) = mpsc::unbounded_channel();
+
         // Initialize members here
-        Self { queue, config_store }
+        Self {
+            queue,
+            config_store,
+            outbox,
+            receipts,
+            rate_limiter,
+            callbacks,
+            worker,
+            schedule: Schedule::default(),
+            lanes: Lanes::new(concurrency),
+            dispatched: HashMap::new(),
+            backlog: HashSet::new(),
+            reports,
+            finished,
+            updates,
+            updated,
+            typing_refreshes: HashMap::new(),
+            challenge: None,
+            held: BTreeSet::new(),
+        }
+    }
+
+    /// Runs the service until the queue is closed.
+    ///
+    /// A single registered [`Manager`] is loaded once and kept for the lifetime of the
+    /// service, with workers sending through clones of it. Presage futures are not `Send`,
+    /// so everything runs on a [`task::LocalSet`].
+    pub async fn run(self) -> anyhow::Result<()> {
+        let local = task::LocalSet::new();
+        local.run_until(self.serve()).await
+    }
+
+    async fn serve(mut self) -> anyhow::Result<()> {
+        let mut manager = Manager::load_registered(self.config_store.clone())
+            .await
+            .context("failed to load registered manager")?;
+
+        // The receiving side owns the websocket, which the sending side reuses.
+        let receiving_manager = manager.clone();
+        task::spawn_local(Self::keep_receiving(
+            receiving_manager,
+            self.outbox.clone(),
+            self.receipts,
+            self.callbacks.clone(),
+        ));
+
+        let pending = self.outbox.recover().context("failed to recover outbox")?;
+        if !pending.is_empty() {
+            info!("replaying {} pending outbox entries", pending.len());
+        }
+        for (id, due) in pending {
+            match due {
+                Some(at) => self.schedule.push(at, id),
+                None => self.deliver(&manager, id).await,
+            }
+        }
+        self.queue.set_backlog(self.backlog.len());
+
+        loop {
+            tokio::select! {
+                command = self.queue.recv() => match command {
+                    Some(command) => self.process(&mut manager, command).await,
+                    None => break,
+                },
+                id = self.schedule.next() => self.deliver(&manager, id).await,
+                Some(report) = self.finished.recv() => self.finish(&manager, report),
+                Some(update) = self.updated.recv() => self.update(&manager, update),
+            }
+            self.queue.set_backlog(self.backlog.len());
+        }
+
+        // Let the sends in progress complete, so they are not repeated on the next start.
+        while self.lanes.busy() > 0 {
+            let Some(report) = self.finished.recv().await else {
+                break;
+            };
+            self.lanes.done(&report.lane);
+        }
+
+        Ok(())
     }
 
-    pub async fn run(mut self) {
-        while let Some(req) = self.queue.recv().await {
-            self.process(req).await;
+    /// Keeps the receive stream open, reconnecting whenever it ends or fails.
+    async fn keep_receiving(
+        mut manager: Manager<C, Registered>,
+        outbox: Outbox,
+        receipts: ReceiptPolicy,
+        callbacks: Callbacks,
+    ) {
+        loop {
+            match Self::receive(&mut manager, &outbox, receipts, &callbacks, false).await {
+                Ok(()) => warn!("message stream ended, reconnecting"),
+                Err(e) => error!("error while receiving stuff: {e}"),
+            }
+            sleep(RECONNECT_DELAY).await;
         }
     }
 
-    async fn process(&mut self, req: (String, String)) {
-        // Spawn and run a new task here that will process the request.
-        // Use a new runtime for this task, and make sure it all runs in the same thread
-        // despite any await
-        let mut manager = Manager::load_registered(self.config_store.clone()).await.unwrap();
+    /// Acts on a command. Commands that send to Signal do so in tasks of their own, so a
+    /// slow send does not hold up the others.
+    async fn process(&mut self, manager: &mut Manager<C, Registered>, command: Command) {
+        match command {
+            Command::Deliver(id) => self.deliver(manager, id).await,
+            Command::Typing {
+                destination,
+                started,
+                refresh_for,
+                reply,
+            } => self.typing(manager, destination, started, refresh_for, reply),
+            Command::MarkRead {
+                author,
+                timestamps,
+                reply,
+            } => {
+                let worker = self.worker.clone();
+                let outbox = self.outbox.clone();
+                let updates = self.updates.clone();
+                let mut manager = manager.clone();
+                task::spawn_local(async move {
+                    let result = match worker.thread(&manager, &author).await {
+                        Ok(Thread::Contact(uuid)) => {
+                            Self::send_receipt(&mut manager, &outbox, uuid, Type::Read, timestamps)
+                                .await
+                        }
+                        Ok(Thread::Group(_)) => Err(SendError::UnknownRecipient),
+                        Err(e) => Err(e),
+                    };
+                    Self::report_failure(&updates, &result);
+                    command::respond(reply, result);
+                });
+            }
+            Command::Challenge { reply } => {
+                let challenge = self.challenge.clone().map(|challenge| Challenge {
+                    held: self.held.len() + self.lanes.waiting(),
+                    ..challenge
+                });
+                command::respond(reply, Ok(challenge));
+            }
+            Command::AnswerChallenge { captcha, reply } => {
+                self.answer_challenge(manager, captcha, reply)
+            }
+        }
+    }
 
-        let destination = Uuid::parse_str(req.0.as_str()).unwrap();
+    /// Acts on what a task sending on behalf of a command reports.
+    fn update(&mut self, manager: &Manager<C, Registered>, update: Update) {
+        match update {
+            Update::Failed(error) => self.note_challenge(Some(&error)),
+            Update::Answered { token, reply } => {
+                // Unless Signal asked again in the meantime.
+                if self
+                    .challenge
+                    .as_ref()
+                    .is_some_and(|challenge| challenge.token == token)
+                {
+                    info!(
+                        "challenge answered, resuming {} held outbox entries",
+                        self.held.len()
+                    );
+                    self.challenge = None;
+                    let now = Utc::now();
+                    for id in std::mem::take(&mut self.held) {
+                        self.schedule.push(now, id);
+                    }
+                    self.dispatch(manager);
+                }
+                command::respond(reply, Ok(true));
+            }
+        }
+    }
 
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis() as u64;
+    /// Reports a failed send on behalf of a command, which may be a challenge, to the
+    /// service.
+    fn report_failure<T>(updates: &mpsc::UnboundedSender<Update>, result: &Result<T, SendError>) {
+        if let Err(e) = result {
+            // Only fails once the service is gone.
+            let _ = updates.send(Update::Failed(e.clone()));
+        }
+    }
 
-        let message = ContentBody::DataMessage(DataMessage {
-            body: Some(req.1),
-            timestamp: Some(timestamp),
-            ..Default::default()
+    /// Pauses sending if Signal asked for proof of humanity.
+    ///
+    /// The challenge token is logged, so the captcha can be answered from the logs too.
+    fn note_challenge(&mut self, error: Option<&SendError>) {
+        let Some(SendError::ProofRequired { token, options }) = error else {
+            return;
+        };
+
+        warn!(
+            "Signal requires proof of humanity, sending is paused until the challenge is answered; \
+             token {token}, options {options:?}"
+        );
+        let since = self.challenge.as_ref().map_or_else(Utc::now, |challenge| challenge.since);
+        self.challenge = Some(Challenge {
+            token: token.clone(),
+            options: options.clone(),
+            since,
+            held: 0,
         });
+    }
 
-        let local = tokio::task::LocalSet::new();
-        local.run_until(async move {
-            let mut receiving_manager = manager.clone();
-            task::spawn_local(async move {
-                if let Err(e) = Self::receive(&mut receiving_manager, false).await {
-                    error!("error while receiving stuff: {e}");
+    /// Submits a captcha for the pending challenge in the background. The held entries are
+    /// sent once it is accepted.
+    fn answer_challenge(
+        &self,
+        manager: &Manager<C, Registered>,
+        captcha: String,
+        reply: command::Reply<bool>,
+    ) {
+        let Some(challenge) = &self.challenge else {
+            command::respond(reply, Ok(false));
+            return;
+        };
+
+        let token = challenge.token.clone();
+        let manager = manager.clone();
+        let updates = self.updates.clone();
+        task::spawn_local(async move {
+            match manager.submit_recaptcha_challenge(&token, &captcha).await {
+                Ok(_) => {
+                    // Only fails once the service is gone.
+                    let _ = updates.send(Update::Answered { token, reply });
                 }
+                Err(e) => command::respond(reply, Err(e.into())),
+            }
+        });
+    }
+
+    /// Sends a delivery or read receipt for messages `sender` sent.
+    async fn send_receipt(
+        manager: &mut Manager<C, Registered>,
+        outbox: &Outbox,
+        sender: Uuid,
+        kind: Type,
+        timestamps: Vec<u64>,
+    ) -> Result<(), SendError> {
+        let receipt = ReceiptMessage {
+            r#type: Some(kind as i32),
+            timestamp: timestamps,
+        };
+        let thread = Thread::Contact(sender);
+
+        Self::send_content(
+            manager,
+            &thread,
+            ContentBody::ReceiptMessage(receipt),
+            outbox.next_timestamp(),
+        )
+        .await
+    }
+
+    /// Acknowledges an incoming message as the receipt policy asks for.
+    async fn acknowledge(
+        manager: &mut Manager<C, Registered>,
+        outbox: &Outbox,
+        receipts: ReceiptPolicy,
+        content: &Content,
+    ) {
+        if !receipts.delivery() || !matches!(content.body, ContentBody::DataMessage(_)) {
+            return;
+        }
+
+        let sender = content.metadata.sender.uuid;
+        let timestamps = vec![content.metadata.timestamp];
+        let kinds = [Some(Type::Delivery), receipts.read().then_some(Type::Read)];
+        for kind in kinds.into_iter().flatten() {
+            if let Err(e) =
+                Self::send_receipt(manager, outbox, sender, kind, timestamps.clone()).await
+            {
+                warn!("failed to send {kind:?} receipt to {sender}: {e}");
+            }
+        }
+    }
+
+    /// Shows or hides the typing indicator in the background, optionally refreshing it
+    /// until stopped.
+    ///
+    /// A command for a destination stops the refresh started by the one before.
+    fn typing(
+        &mut self,
+        manager: &Manager<C, Registered>,
+        destination: Destination,
+        started: bool,
+        refresh_for: Option<Duration>,
+        reply: command::Reply<()>,
+    ) {
+        if let Some(refresh) = self.typing_refreshes.remove(&destination) {
+            refresh.abort();
+        }
+
+        let worker = self.worker.clone();
+        let outbox = self.outbox.clone();
+        let updates = self.updates.clone();
+        let mut manager = manager.clone();
+        let key = destination.clone();
+        let sent = task::spawn_local(async move {
+            let result = async {
+                let thread = worker.thread(&manager, &destination).await?;
+                Self::send_typing(&mut manager, &outbox, &thread, started).await?;
+                Ok::<_, SendError>(thread)
+            }
+            .await;
+            Self::report_failure(&updates, &result);
+            command::respond(reply, result.as_ref().map(|_| ()).map_err(Clone::clone));
+            result.ok().map(|thread| (manager, outbox, thread))
+        });
+
+        let (true, Some(duration)) = (started, refresh_for) else {
+            return;
+        };
+        // Aborting the refresh leaves the first send alone.
+        let refresh = task::spawn_local(async move {
+            let Ok(Some((mut manager, outbox, thread))) = sent.await else {
+                return;
+            };
+            let deadline = Instant::now() + duration;
+            loop {
+                sleep(TYPING_REFRESH_INTERVAL).await;
+                if Instant::now() >= deadline {
+                    break;
+                }
+                if let Err(e) = Self::send_typing(&mut manager, &outbox, &thread, true).await {
+                    warn!("failed to refresh typing indicator in {thread}: {e}");
+                }
+            }
+            if let Err(e) = Self::send_typing(&mut manager, &outbox, &thread, false).await {
+                warn!("failed to stop typing indicator in {thread}: {e}");
+            }
+        });
+        self.typing_refreshes.insert(key, refresh);
+    }
+
+    async fn send_typing(
+        manager: &mut Manager<C, Registered>,
+        outbox: &Outbox,
+        thread: &Thread,
+        started: bool,
+    ) -> Result<(), SendError> {
+        let timestamp = outbox.next_timestamp();
+        let action = if started { Action::Started } else { Action::Stopped };
+        let typing = TypingMessage {
+            timestamp: Some(timestamp),
+            action: Some(action as i32),
+            group_id: None,
+        };
+
+        Self::send_content(manager, thread, ContentBody::TypingMessage(typing), timestamp).await
+    }
+
+    /// Queues a pending outbox entry for a worker, once it is due and within the rate
+    /// limits.
+    ///
+    /// A retried entry is still first in its lane, which waits for it to be due again.
+    async fn deliver(&mut self, manager: &Manager<C, Registered>, id: u64) {
+        let parked = match self.dispatched.get(&id) {
+            Some(lane) if self.lanes.parked(lane) == Some(&id) => Some(lane.clone()),
+            // Already waiting for or being sent by a worker.
+            Some(_) => return,
+            None => None,
+        };
+        let pending = match self.outbox.get(id) {
+            Ok(Some(entry)) if entry.state == State::Pending => entry,
+            // Already delivered, e.g. queued again while being replayed, or cancelled.
+            result => {
+                if let Err(e) = result {
+                    error!("failed to load outbox entry {id}: {e}");
+                }
+                self.backlog.remove(&id);
+                // The worker finds nothing to send, and lets the lane go on.
+                if let Some(lane) = parked {
+                    self.lanes.resume(&lane);
+                    self.dispatch(manager);
+                }
+                return;
+            }
+        };
+
+        // Scheduled for later, or rescheduled since it was queued.
+        if let Some(at) = pending.next_attempt_at.filter(|at| *at > Utc::now()) {
+            self.schedule.push(at, id);
+            return;
+        }
+        self.backlog.insert(id);
+
+        // Paused by a challenge, the entry waits for it to be answered.
+        if self.challenge.is_some() {
+            self.held.insert(id);
+            return;
+        }
+
+        // Over a rate limit, the entry waits in the outbox until there is room again.
+        let (lane, members) = self.lane(manager, &pending.destination).await;
+        if let Some(wait) = self.rate_limiter.acquire(id, &lane, members.len().max(1)) {
+            // Only out of range for absurdly low rates.
+            let wait =
+                chrono::Duration::from_std(wait).unwrap_or_else(|_| chrono::Duration::days(1));
+            let at = Utc::now() + wait;
+            debug!("rate limit reached, outbox entry {id} waits until {at}");
+            self.schedule.push(at, id);
+            return;
+        }
+
+        match parked {
+            Some(lane) => self.lanes.resume(&lane),
+            None => {
+                self.dispatched.insert(id, lane.clone());
+                self.lanes.push(lane, id, members);
+            }
+        }
+        self.dispatch(manager);
+    }
+
+    /// Resolves the lane of the thread messages to `destination` are sent to, along with
+    /// the lanes of the contacts a group message reaches.
+    ///
+    /// A destination that does not resolve gets a lane of its own, and fails once sent.
+    async fn lane(
+        &self,
+        manager: &Manager<C, Registered>,
+        destination: &Destination,
+    ) -> (Destination, Vec<Destination>) {
+        let Ok(thread) = self.worker.thread(manager, destination).await else {
+            return (destination.clone(), Vec::new());
+        };
+        let members = match &thread {
+            Thread::Contact(_) => Vec::new(),
+            Thread::Group(_) => Worker::<C>::recipients(manager, &thread)
+                .unwrap_or_default()
+                .into_iter()
+                .map(Destination::Contact)
+                .collect(),
+        };
+
+        (Destination::from(&thread), members)
+    }
+
+    /// Hands due entries to workers while there is room, unless a challenge paused sending.
+    ///
+    /// Each entry is sent in a task of its own. Entries for the same thread are sent one
+    /// after the other, in the order they became due, and a group message is not sent
+    /// while a message to any of its members is, as both would use the same sessions.
+    fn dispatch(&mut self, manager: &Manager<C, Registered>) {
+        if self.challenge.is_some() {
+            return;
+        }
+
+        while let Some((lane, id)) = self.lanes.next() {
+            let worker = self.worker.clone();
+            let mut manager = manager.clone();
+            let reports = self.reports.clone();
+            task::spawn_local(async move {
+                let report = worker.deliver(&mut manager, lane, id).await;
+                // Only fails once the service is gone.
+                let _ = reports.send(report);
             });
+        }
+    }
+
+    /// Acts on what a worker reports, and lets the next entry in the lane go.
+    fn finish(&mut self, manager: &Manager<C, Registered>, report: Report) {
+        let Report {
+            id,
+            lane,
+            outcome,
+            error,
+        } = report;
+        self.note_challenge(error.as_ref());
+
+        match outcome {
+            // Stays first in its lane, so later entries do not overtake it.
+            Some(Outcome::Retry(at)) => {
+                self.lanes.park(lane, id);
+                self.backlog.remove(&id);
+                self.schedule.push(at, id);
+            }
+            // Sent again first, once the challenge is answered.
+            Some(Outcome::Held) => self.lanes.give_back(lane, id),
+            Some(Outcome::Sent | Outcome::DeadLettered) => {
+                self.dispatched.remove(&id);
+                self.backlog.remove(&id);
+                self.lanes.done(&lane);
+            }
+            // Not sent, e.g. rescheduled or cancelled while waiting in its lane. Handed back
+            // to the schedule, which finds out when it is due, if ever.
+            None => {
+                self.dispatched.remove(&id);
+                self.backlog.remove(&id);
+                self.lanes.done(&lane);
+                self.schedule.push(Utc::now(), id);
+            }
+        }
+        self.dispatch(manager);
+    }
+
+    /// Records a delivery status and reports the changes to the entry's status callback.
+    fn track(
+        outbox: &Outbox,
+        callbacks: &Callbacks,
+        entry: &Entry,
+        recipients: &[Uuid],
+        status: DeliveryStatus,
+    ) {
+        let changes = match outbox.advance(entry.id, recipients, status) {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("failed to record delivery of outbox entry {}: {e}", entry.id);
+                return;
+            }
+        };
+
+        if let Some(url) = &entry.message.status_callback {
+            for (recipient, status) in changes {
+                let change = StatusChange::new(entry.id, entry.timestamp, recipient, status);
+                callbacks.notify(url, change);
+            }
+        }
+    }
+
+    /// Updates the delivery status of the messages an incoming receipt refers to.
+    fn track_receipt(outbox: &Outbox, callbacks: &Callbacks, content: &Content) {
+        let ContentBody::ReceiptMessage(receipt) = &content.body else {
+            return;
+        };
+        let status = match receipt.r#type() {
+            Type::Delivery => DeliveryStatus::Delivered,
+            Type::Read => DeliveryStatus::Read,
+            Type::Viewed => return,
+        };
+
+        let sender = content.metadata.sender.uuid;
+        for timestamp in &receipt.timestamp {
+            match outbox.sent_with(*timestamp) {
+                Ok(Some(entry)) => Self::track(outbox, callbacks, &entry, &[sender], status),
+                // Not sent from here, e.g. by a linked device.
+                Ok(None) => {}
+                Err(e) => error!("failed to look up message sent at {timestamp}: {e}"),
+            }
+        }
+    }
+
+    /// Sends content to a contact or group, adding the group context if needed.
+    async fn send_content(
+        manager: &mut Manager<C, Registered>,
+        thread: &Thread,
+        mut content: ContentBody,
+        timestamp: u64,
+    ) -> Result<(), SendError> {
+        match thread {
+            Thread::Contact(uuid) => manager.send_message(*uuid, content, timestamp).await?,
+            Thread::Group(master_key) => {
+                let group = manager.group(master_key)?.ok_or(SendError::UnknownRecipient)?;
+                let context = GroupContextV2 {
+                    master_key: Some(master_key.to_vec()),
+                    revision: Some(group.revision),
+                    ..Default::default()
+                };
+                match &mut content {
+                    ContentBody::DataMessage(message)
+                    | ContentBody::EditMessage(EditMessage {
+                        data_message: Some(message),
+                        ..
+                    }) => message.group_v2 = Some(context),
+                    ContentBody::TypingMessage(typing) => {
+                        let identifier = GroupSecretParams::derive_from_master_key(
+                            GroupMasterKey::new(*master_key),
+                        )
+                        .get_group_identifier();
+                        typing.group_id = Some(identifier.to_vec());
+                    }
+                    _ => {}
+                }
+                manager
+                    .send_message_to_group(master_key, content, timestamp)
+                    .await?
+            }
+        }
+        Ok(())
+    }
 
-            sleep(Duration::from_secs(4)).await;
+    /// Looks up a group by master key, or else by group identifier.
+    fn find_group(
+        manager: &Manager<C, Registered>,
+        key: &[u8; GROUP_KEY_LENGTH],
+    ) -> Result<([u8; GROUP_KEY_LENGTH], Group), SendError> {
+        if let Some(group) = manager.group(key)? {
+            return Ok((*key, group));
+        }
 
-            manager.send_message(destination, message, timestamp).await.unwrap();
-        }).await;
+        for item in manager.groups()? {
+            let (master_key, group) = item.map_err(|e| SendError::Other(e.to_string()))?;
+            let identifier =
+                GroupSecretParams::derive_from_master_key(GroupMasterKey::new(master_key))
+                    .get_group_identifier();
+            if &identifier == key {
+                return Ok((master_key, group));
+            }
+        }
 
+        Err(SendError::UnknownRecipient)
     }
 
     async fn receive(
         manager: &mut Manager<C, Registered>,
+        outbox: &Outbox,
+        receipts: ReceiptPolicy,
+        callbacks: &Callbacks,
         notifications: bool,
     ) -> anyhow::Result<()> {
         let attachments_tmp_dir = Builder::new().prefix("presage-attachments").tempdir()?;
@@ -92,11 +820,54 @@ impl<C: Store + 'static> SignalServiceWrapper<C> {
         while let Some(content) = messages.next().await {
             Self::process_incoming_message(manager, attachments_tmp_dir.path(), notifications, &content)
                 .await;
+            Self::acknowledge(manager, outbox, receipts, &content).await;
+            Self::track_receipt(outbox, callbacks, &content);
+            Self::track_expire_timer(outbox, &content);
         }
     
         Ok(())
     }
 
+    /// Records the disappearing-messages timer of a thread when it is changed from a phone,
+    /// ours or the other side's, so messages sent from here keep it.
+    ///
+    /// Besides explicit timer updates, clients take the timer of every message with content
+    /// as the timer of the thread, no timer meaning off.
+    fn track_expire_timer(outbox: &Outbox, content: &Content) {
+        let message = match &content.body {
+            ContentBody::DataMessage(message)
+            | ContentBody::SynchronizeMessage(SyncMessage {
+                sent:
+                    Some(Sent {
+                        message: Some(message),
+                        ..
+                    }),
+                ..
+            }) => message,
+            _ => return,
+        };
+        let update = message.flags() & Flags::ExpirationTimerUpdate as u32 != 0;
+        if !update && message.body.is_none() && message.attachments.is_empty() {
+            return;
+        }
+        let Ok(thread) = Thread::try_from(content) else {
+            return;
+        };
+
+        let seconds = message.expire_timer.unwrap_or_default();
+        let thread = Destination::from(&thread);
+        match outbox.expire_timer(&thread) {
+            Ok(current) if current.unwrap_or_default() == seconds => {}
+            Ok(_) => {
+                info!("disappearing-messages timer of {thread} changed to {seconds} seconds");
+                if let Err(e) = outbox.set_expire_timer(&thread, seconds) {
+                    error!("failed to store expire timer of {thread}: {e}");
+                }
+            }
+            Err(e) => error!("failed to load expire timer of {thread}: {e}"),
+        }
+    }
+
     // Note to developers, this is a good example of a function you can use as a source of inspiration
     // to process incoming messages.
     async fn process_incoming_message(
@@ -268,3 +1039,380 @@ impl<C: Store + 'static> SignalServiceWrapper<C> {
         }
     }
 }
+
+/// What a worker reports back once it is done with an outbox entry.
+struct Report {
+    id: u64,
+    /// The lane the entry was sent in.
+    lane: Destination,
+    /// What became of the entry, if it was still pending and due, and the outcome was
+    /// recorded.
+    outcome: Option<Outcome>,
+    error: Option<SendError>,
+}
+
+/// What a task sending on behalf of a command reports back to the service.
+enum Update {
+    /// A send failed, maybe because Signal asks for proof of humanity.
+    Failed(SendError),
+    /// Signal accepted a captcha for the challenge with this token.
+    Answered {
+        token: String,
+        reply: command::Reply<bool>,
+    },
+}
+
+/// Sends outbox entries on behalf of the service, each in a task of its own.
+#[derive(Clone)]
+struct Worker<C: Store + 'static> {
+    config_store: C,
+    outbox: Outbox,
+    retry_policy: RetryPolicy,
+    callbacks: Callbacks,
+    resolver: Rc<dyn Resolver>,
+    /// Locks held while attachments are uploaded, by attachment id, so entries sharing an
+    /// attachment upload it once.
+    uploads: Rc<RefCell<HashMap<u64, Weak<Mutex<()>>>>>,
+}
+
+impl<C: Store + 'static> Worker<C> {
+    /// Sends a pending outbox entry and records the outcome.
+    async fn deliver(
+        &self,
+        manager: &mut Manager<C, Registered>,
+        lane: Destination,
+        id: u64,
+    ) -> Report {
+        let mut report = Report {
+            id,
+            lane,
+            outcome: None,
+            error: None,
+        };
+        let entry = match self.outbox.begin(id) {
+            Ok(Some(entry)) => entry,
+            // Already delivered, e.g. queued again while being replayed, or rescheduled or
+            // cancelled while waiting in its lane.
+            Ok(None) => return report,
+            Err(e) => {
+                error!("failed to load outbox entry {id}: {e}");
+                return report;
+            }
+        };
+
+        let result = self.send(manager, &entry).await;
+        if let Err(e) = &result {
+            warn!("failed to deliver outbox entry {id}: {e}");
+        }
+        report.error = result.as_ref().err().cloned();
+        match self
+            .outbox
+            .finish(entry.clone(), result, &self.retry_policy)
+        {
+            Ok(outcome) => {
+                match outcome {
+                    Outcome::Sent => self.track_outcome(&entry, DeliveryStatus::Sent),
+                    Outcome::Retry(at) => info!("retrying outbox entry {id} at {at}"),
+                    Outcome::DeadLettered => {
+                        warn!("outbox entry {id} moved to dead letters");
+                        self.track_outcome(&entry, DeliveryStatus::Failed);
+                    }
+                    Outcome::Held => {
+                        info!("outbox entry {id} held until the challenge is answered")
+                    }
+                }
+                report.outcome = Some(outcome);
+            }
+            Err(e) => error!("failed to record outcome of outbox entry {id}: {e}"),
+        }
+
+        report
+    }
+
+    /// Moves every known recipient of an entry to `status`.
+    fn track_outcome(&self, entry: &Entry, status: DeliveryStatus) {
+        let recipients = match self.outbox.delivery(entry.id) {
+            Ok(delivery) => delivery.into_keys().collect::<Vec<_>>(),
+            Err(e) => {
+                error!("failed to load delivery of outbox entry {}: {e}", entry.id);
+                return;
+            }
+        };
+        SignalServiceWrapper::<C>::track(&self.outbox, &self.callbacks, entry, &recipients, status);
+    }
+
+    async fn send(
+        &self,
+        manager: &mut Manager<C, Registered>,
+        entry: &Entry,
+    ) -> Result<(), SendError> {
+        // Assigned by `Outbox::begin`.
+        let timestamp = entry.timestamp.ok_or_else(|| {
+            SendError::Other(format!("outbox entry {} has no timestamp", entry.id))
+        })?;
+        let thread = self.thread(manager, &entry.destination).await?;
+        let recipients = Self::recipients(manager, &thread)?;
+        SignalServiceWrapper::<C>::track(
+            &self.outbox,
+            &self.callbacks,
+            entry,
+            &recipients,
+            DeliveryStatus::Queued,
+        );
+
+        let message = DataMessage {
+            body: entry.message.body.clone(),
+            attachments: self
+                .attachment_pointers(manager, &entry.message.attachments)
+                .await?,
+            quote: entry
+                .message
+                .quote
+                .as_ref()
+                .map(|quote| Self::quote(manager, &thread, quote)),
+            reaction: entry.message.reaction.as_ref().map(|reaction| Reaction {
+                emoji: Some(reaction.emoji.clone()),
+                remove: Some(reaction.remove),
+                target_author_uuid: Some(reaction.author.to_string()),
+                target_sent_timestamp: Some(reaction.timestamp),
+            }),
+            body_ranges: Self::body_ranges(&entry.message),
+            delete: entry.message.delete.map(|timestamp| Delete {
+                target_sent_timestamp: Some(timestamp),
+            }),
+            expire_timer: self.expire_timer(&thread, &entry.message),
+            flags: entry
+                .message
+                .expiration_update
+                .then_some(Flags::ExpirationTimerUpdate as u32),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+
+        let content = match entry.message.edit {
+            Some(original) => ContentBody::EditMessage(EditMessage {
+                target_sent_timestamp: Some(original),
+                data_message: Some(message),
+            }),
+            None => ContentBody::DataMessage(message),
+        };
+        SignalServiceWrapper::<C>::send_content(manager, &thread, content, timestamp).await?;
+
+        if let Some(timestamp) = entry.message.delete {
+            self.forget_message(&thread, timestamp);
+        }
+        // Clients take the timer of a message to a contact as the timer of the chat.
+        let overridden =
+            matches!(thread, Thread::Contact(_)) && entry.message.expire_timer.is_some();
+        if entry.message.expiration_update || overridden {
+            let seconds = entry.message.expire_timer.unwrap_or_default();
+            if let Err(e) = self.outbox.set_expire_timer(&(&thread).into(), seconds) {
+                error!("failed to store expire timer of {thread}: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// The timer a message is sent with: its own, or else the one set for the thread, from
+    /// here or from a phone.
+    ///
+    /// Clients take a message without a timer as turning the thread's timer off, so every
+    /// message carries it.
+    fn expire_timer(&self, thread: &Thread, message: &Outgoing) -> Option<u32> {
+        message.expire_timer.or_else(|| {
+            self.outbox
+                .expire_timer(&thread.into())
+                .unwrap_or_else(|e| {
+                    warn!("failed to load expire timer of {thread}: {e}");
+                    None
+                })
+        })
+    }
+
+    /// Turns the mentions and styles of a message into body ranges.
+    fn body_ranges(message: &Outgoing) -> Vec<BodyRange> {
+        let mentions = message.mentions.iter().map(|mention| BodyRange {
+            start: Some(mention.start),
+            length: Some(mention.length),
+            associated_value: Some(AssociatedValue::MentionUuid(mention.uuid.to_string())),
+        });
+        let styles = message.styles.iter().map(|range| BodyRange {
+            start: Some(range.start),
+            length: Some(range.length),
+            associated_value: Some(AssociatedValue::Style(match range.style {
+                TextStyle::Bold => Style::Bold,
+                TextStyle::Italic => Style::Italic,
+                TextStyle::Spoiler => Style::Spoiler,
+                TextStyle::Strikethrough => Style::Strikethrough,
+                TextStyle::Monospace => Style::Monospace,
+            } as i32)),
+        });
+
+        mentions.chain(styles).collect()
+    }
+
+    /// Removes a message deleted for everyone from the local store.
+    fn forget_message(&self, thread: &Thread, timestamp: u64) {
+        let mut store = self.config_store.clone();
+        match store.delete_message(thread, timestamp) {
+            Ok(true) => info!("deleted message in {thread} sent at {timestamp}"),
+            Ok(false) => warn!("no message in {thread} sent at {timestamp} to delete"),
+            Err(e) => error!("failed to delete message in {thread} sent at {timestamp}: {e}"),
+        }
+    }
+
+    /// Resolves a destination to the thread messages for it are stored in.
+    async fn thread(
+        &self,
+        manager: &Manager<C, Registered>,
+        destination: &Destination,
+    ) -> Result<Thread, SendError> {
+        Ok(match destination {
+            Destination::Contact(uuid) => Thread::Contact(*uuid),
+            Destination::PhoneNumber(number) => Thread::Contact(self.resolve(number).await?),
+            Destination::Group(key) => {
+                Thread::Group(SignalServiceWrapper::<C>::find_group(manager, key)?.0)
+            }
+        })
+    }
+
+    /// Lists who a message sent to the thread reaches.
+    fn recipients(
+        manager: &Manager<C, Registered>,
+        thread: &Thread,
+    ) -> Result<Vec<Uuid>, SendError> {
+        Ok(match thread {
+            Thread::Contact(uuid) => vec![*uuid],
+            Thread::Group(master_key) => manager
+                .group(master_key)?
+                .ok_or(SendError::UnknownRecipient)?
+                .members
+                .into_iter()
+                .map(|member| member.uuid)
+                .collect(),
+        })
+    }
+
+    /// Builds a quote of an earlier message, including its text when it is in the store.
+    fn quote(manager: &Manager<C, Registered>, thread: &Thread, target: &QuoteTarget) -> Quote {
+        let text = match manager.message(thread, target.timestamp) {
+            Ok(Some(Content {
+                body: ContentBody::DataMessage(DataMessage { body, .. }),
+                ..
+            })) => body,
+            Ok(Some(Content {
+                body:
+                    ContentBody::SynchronizeMessage(SyncMessage {
+                        sent:
+                            Some(Sent {
+                                message: Some(DataMessage { body, .. }),
+                                ..
+                            }),
+                        ..
+                    }),
+                ..
+            })) => body,
+            Ok(_) => {
+                warn!(
+                    "no message in {thread} sent at {} to quote",
+                    target.timestamp
+                );
+                None
+            }
+            Err(e) => {
+                warn!("failed to load quoted message: {e}");
+                None
+            }
+        };
+
+        Quote {
+            id: Some(target.timestamp),
+            author_uuid: Some(target.author.to_string()),
+            text,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the pointers to the given attachments, uploading those not uploaded yet.
+    async fn attachment_pointers(
+        &self,
+        manager: &Manager<C, Registered>,
+        ids: &[u64],
+    ) -> Result<Vec<AttachmentPointer>, SendError> {
+        let storage = |e: anyhow::Error| SendError::Other(format!("attachment storage: {e}"));
+        let missing = |id| SendError::Other(format!("attachment {id} is missing"));
+
+        let mut pointers = Vec::with_capacity(ids.len());
+        for &id in ids {
+            // Another entry may be uploading it, so its pointer is read once that is done.
+            let lock = self.upload_lock(id);
+            let _uploading = lock.lock().await;
+
+            let attachment = self
+                .outbox
+                .attachment(id)
+                .map_err(storage)?
+                .ok_or_else(|| missing(id))?;
+            if let Some(pointer) = &attachment.pointer {
+                let pointer = AttachmentPointer::decode(pointer.as_slice())
+                    .map_err(|e| SendError::Other(format!("corrupt attachment {id}: {e}")))?;
+                pointers.push(pointer);
+                continue;
+            }
+
+            let data = self
+                .outbox
+                .attachment_data(id)
+                .map_err(storage)?
+                .ok_or_else(|| missing(id))?;
+            let spec = AttachmentSpec {
+                content_type: attachment.content_type.clone(),
+                length: data.len(),
+                file_name: attachment.file_name.clone(),
+                preview: None,
+                voice_note: None,
+                borderless: None,
+                width: None,
+                height: None,
+                caption: None,
+                blur_hash: None,
+            };
+            let pointer = manager
+                .upload_attachments(vec![(spec, data.to_vec())])
+                .await?
+                .pop()
+                .ok_or_else(|| missing(id))?
+                .map_err(|e| {
+                    SendError::Network(format!("failed to upload attachment {id}: {e}"))
+                })?;
+
+            self.outbox
+                .attachment_uploaded(attachment, pointer.encode_to_vec())
+                .map_err(storage)?;
+            pointers.push(pointer);
+        }
+
+        Ok(pointers)
+    }
+
+    /// The lock held while uploading attachment `id`.
+    fn upload_lock(&self, id: u64) -> Rc<Mutex<()>> {
+        let mut uploads = self.uploads.borrow_mut();
+        uploads.retain(|_, lock| lock.strong_count() > 0);
+        if let Some(lock) = uploads.get(&id).and_then(Weak::upgrade) {
+            return lock;
+        }
+
+        let lock = Rc::new(Mutex::new(()));
+        uploads.insert(id, Rc::downgrade(&lock));
+        lock
+    }
+
+    async fn resolve(&self, number: &PhoneNumber) -> Result<Uuid, SendError> {
+        self.resolver
+            .resolve(number)
+            .await
+            .map_err(|e| SendError::Other(format!("failed to resolve phone number: {e}")))?
+            .ok_or(SendError::UnknownRecipient)
+    }
+}
@@ -0,0 +1,264 @@
+use presage::prelude::Uuid;
+
+use crate::outbox::{Mention, StyleRange, TextStyle};
+
+/// Character Signal clients render a mention in place of.
+pub const MENTION_PLACEHOLDER: char = '\u{FFFC}';
+
+/// Markdown delimiters understood by [`render`], longest first so `**` wins over `*`.
+const DELIMITERS: [(&str, TextStyle); 6] = [
+    ("**", TextStyle::Bold),
+    ("~~", TextStyle::Strikethrough),
+    ("||", TextStyle::Spoiler),
+    ("`", TextStyle::Monospace),
+    ("*", TextStyle::Italic),
+    ("_", TextStyle::Italic),
+];
+
+/// Message text with its markup turned into body ranges.
+#[derive(Debug, Default)]
+pub struct Rendered {
+    pub text: String,
+    pub mentions: Vec<Mention>,
+    pub styles: Vec<StyleRange>,
+}
+
+/// Length of `s` in UTF-16 code units, the unit of Signal body ranges.
+pub fn utf16_len(s: &str) -> u32 {
+    s.encode_utf16().count() as u32
+}
+
+/// Replaces every `@{uuid}` token in `content` with a mention placeholder and, when
+/// `markdown` is set, strips the supported Markdown subset into style ranges.
+///
+/// The subset is `**bold**`, `*italic*` or `_italic_`, `~~strikethrough~~`,
+/// `` `monospace` `` and `||spoiler||`. A backslash escapes the next character, text in
+/// backticks is taken literally and delimiters without a closing match are kept as text.
+/// Braces that do not hold a UUID are left as they are.
+pub fn render(content: &str, markdown: bool) -> Rendered {
+    let mut rendered = Rendered::default();
+    // Where each style was opened, in UTF-16 units and in bytes of the rendered text.
+    let mut open: Vec<(&str, TextStyle, u32, usize)> = Vec::new();
+    let mut length = 0;
+    let mut rest = content;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some((uuid, token)) = mention_token(rest) {
+            rendered.mentions.push(Mention {
+                uuid,
+                start: length,
+                length: 1,
+            });
+            rendered.text.push(MENTION_PLACEHOLDER);
+            length += 1;
+            rest = &rest[token..];
+            continue;
+        }
+
+        if markdown {
+            if c == '\\' {
+                if let Some(escaped) = rest[1..].chars().next() {
+                    rendered.text.push(escaped);
+                    length += escaped.len_utf16() as u32;
+                    rest = &rest[1 + escaped.len_utf8()..];
+                    continue;
+                }
+            }
+
+            let delimiter = DELIMITERS.iter().find(|(d, _)| rest.starts_with(d));
+            if let Some(&(delimiter, style)) = delimiter {
+                let after = &rest[delimiter.len()..];
+
+                // Closing needs the same boundaries `closing` looked for when opening.
+                let closes = rendered.text.chars().last().is_some_and(|c| !c.is_whitespace())
+                    && !(delimiter == "_" && after.starts_with(char::is_alphanumeric));
+                if let Some(index) = open
+                    .iter()
+                    .position(|(d, ..)| *d == delimiter)
+                    .filter(|_| closes)
+                {
+                    let (_, style, start, _) = open.remove(index);
+                    rendered.styles.push(StyleRange {
+                        style,
+                        start,
+                        length: length - start,
+                    });
+                    rest = after;
+                    continue;
+                }
+
+                if let Some(close) = closing(rendered.text.chars().last(), after, delimiter) {
+                    if style == TextStyle::Monospace {
+                        // No markup inside code spans.
+                        let code = &after[..close];
+                        rendered.text.push_str(code);
+                        rendered.styles.push(StyleRange {
+                            style,
+                            start: length,
+                            length: utf16_len(code),
+                        });
+                        length += utf16_len(code);
+                        rest = &after[close + delimiter.len()..];
+                    } else {
+                        open.push((delimiter, style, length, rendered.text.len()));
+                        rest = after;
+                    }
+                    continue;
+                }
+            }
+        }
+
+        rendered.text.push(c);
+        length += c.len_utf16() as u32;
+        rest = &rest[c.len_utf8()..];
+    }
+
+    // Delimiters whose match was taken by another style are text after all.
+    for (delimiter, _, start, at) in open.into_iter().rev() {
+        rendered.text.insert_str(at, delimiter);
+        let shift = utf16_len(delimiter);
+        for range in &mut rendered.styles {
+            if range.start >= start {
+                range.start += shift;
+            } else if range.start + range.length > start {
+                range.length += shift;
+            }
+        }
+        for mention in &mut rendered.mentions {
+            if mention.start >= start {
+                mention.start += shift;
+            }
+        }
+    }
+
+    rendered
+}
+
+/// Parses a `@{uuid}` token at the start of `s`, returning the UUID and token length.
+fn mention_token(s: &str) -> Option<(Uuid, usize)> {
+    let inner = s.strip_prefix("@{")?;
+    let close = inner.find('}')?;
+    let uuid = Uuid::parse_str(&inner[..close]).ok()?;
+
+    Some((uuid, 2 + close + 1))
+}
+
+/// Finds where the style opened by `delimiter` closes in `after`, if it does.
+///
+/// Emphasis must not be empty or start with whitespace, and `_` only counts at word
+/// boundaries so `snake_case` stays intact.
+fn closing(before: Option<char>, after: &str, delimiter: &str) -> Option<usize> {
+    let first = after.chars().next()?;
+    if first.is_whitespace() || after.starts_with(delimiter) {
+        return None;
+    }
+    if delimiter == "_" && before.is_some_and(char::is_alphanumeric) {
+        return None;
+    }
+
+    after.match_indices(delimiter).map(|(i, _)| i).find(|&i| {
+        let inner = &after[..i];
+        let next = after[i + delimiter.len()..].chars().next();
+        !(inner.ends_with(char::is_whitespace)
+            || (delimiter == "_" && next.is_some_and(char::is_alphanumeric)))
+    })
+}
+
+/// Checks that a mention covers a single placeholder in `text`.
+pub fn check_mention(text: &str, mention: &Mention) -> Result<(), String> {
+    let units: Vec<u16> = text.encode_utf16().collect();
+    let mut placeholder = [0; 1];
+    MENTION_PLACEHOLDER.encode_utf16(&mut placeholder);
+
+    match units.get(mention.start as usize) {
+        Some(unit) if mention.length == 1 && *unit == placeholder[0] => Ok(()),
+        _ => Err(format!(
+            "mention of {} at {} does not cover a U+FFFC placeholder",
+            mention.uuid, mention.start
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn styles(rendered: &Rendered) -> Vec<(TextStyle, u32, u32)> {
+        rendered
+            .styles
+            .iter()
+            .map(|range| (range.style, range.start, range.length))
+            .collect()
+    }
+
+    #[test]
+    fn leaves_plain_text_alone() {
+        let rendered = render("*not* **styled**", false);
+        assert_eq!(rendered.text, "*not* **styled**");
+        assert!(rendered.styles.is_empty());
+    }
+
+    #[test]
+    fn nests_styles() {
+        let rendered = render("**a _b_ c**", true);
+        assert_eq!(rendered.text, "a b c");
+        assert_eq!(
+            styles(&rendered),
+            [(TextStyle::Italic, 2, 1), (TextStyle::Bold, 0, 5)]
+        );
+    }
+
+    #[test]
+    fn escapes_delimiters() {
+        let rendered = render(r"\*not italic\* but \\*italic*", true);
+        assert_eq!(rendered.text, r"*not italic* but \italic");
+        assert_eq!(styles(&rendered), [(TextStyle::Italic, 18, 6)]);
+    }
+
+    #[test]
+    fn keeps_underscores_inside_words() {
+        let rendered = render("snake_case and _italic_", true);
+        assert_eq!(rendered.text, "snake_case and italic");
+        assert_eq!(styles(&rendered), [(TextStyle::Italic, 15, 6)]);
+    }
+
+    #[test]
+    fn takes_code_spans_literally() {
+        let rendered = render("`a *b* _c_` d", true);
+        assert_eq!(rendered.text, "a *b* _c_ d");
+        assert_eq!(styles(&rendered), [(TextStyle::Monospace, 0, 9)]);
+    }
+
+    #[test]
+    fn keeps_unclosed_delimiters_as_text() {
+        let rendered = render("*a `b*` **c**", true);
+        assert_eq!(rendered.text, "*a b* c");
+        assert_eq!(
+            styles(&rendered),
+            [(TextStyle::Monospace, 3, 2), (TextStyle::Bold, 6, 1)]
+        );
+    }
+
+    #[test]
+    fn counts_offsets_in_utf16_units() {
+        let rendered = render("😀 **b** ~~é~~", true);
+        assert_eq!(rendered.text, "😀 b é");
+        assert_eq!(
+            styles(&rendered),
+            [(TextStyle::Bold, 3, 1), (TextStyle::Strikethrough, 5, 1)]
+        );
+        assert_eq!(utf16_len(&rendered.text), 6);
+    }
+
+    #[test]
+    fn turns_tokens_into_mentions_inside_markdown() {
+        let uuid = Uuid::from_u128(1);
+        let rendered = render(&format!("😀 **hi @{{{uuid}}}** @{{x}}"), true);
+        assert_eq!(rendered.text, "😀 hi \u{FFFC} @{x}");
+        assert_eq!(styles(&rendered), [(TextStyle::Bold, 3, 4)]);
+        assert_eq!(rendered.mentions.len(), 1);
+        assert_eq!(rendered.mentions[0].uuid, uuid);
+        assert_eq!(rendered.mentions[0].start, 6);
+        check_mention(&rendered.text, &rendered.mentions[0]).unwrap();
+    }
+}
@@ -0,0 +1,387 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        Path, Query, State,
+    },
+    Json,
+};
+use hyper::StatusCode;
+use presage::prelude::Uuid;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::command::Command;
+use crate::destination::{parse_thread, Destination};
+use crate::outbox::{Outbox, Outgoing, ReactionTarget};
+use crate::problem::Problem;
+use crate::relayer::{dispatch, enqueue, Accepted, Message};
+use crate::signal_service::Queue;
+
+/// Longest emoji accepted in a reaction, in bytes.
+///
+/// Enough for the longest emoji ZWJ sequences.
+const MAX_EMOJI_LENGTH: usize = 64;
+
+/// Most messages marked read in a single request.
+const MAX_READ_TIMESTAMPS: usize = 100;
+
+/// Longest a typing indicator is kept shown without being stopped, in seconds.
+const MAX_TYPING_REFRESH: u64 = 600;
+
+fn thread(thread: &str) -> Result<Destination, Problem> {
+    parse_thread(thread).map_err(|e| Problem::bad_request("invalid-thread", e))
+}
+
+fn parse_author(author: &str) -> Result<Uuid, Problem> {
+    Uuid::parse_str(author).map_err(|e| {
+        Problem::unprocessable(
+            "invalid-author",
+            format!("author {author:?} is not a UUID: {e}"),
+        )
+    })
+}
+
+/// An emoji reaction to a message in a thread.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct Reaction {
+    /// The emoji to react with, or to take back when `remove` is set.
+    emoji: String,
+    /// Take back an earlier reaction with the same emoji.
+    #[serde(default)]
+    remove: bool,
+    /// UUID of the author of the message reacted to.
+    target_author: String,
+    /// The timestamp the message reacted to was sent with.
+    target_timestamp: u64,
+}
+
+impl Reaction {
+    fn validate(self) -> Result<Outgoing, Problem> {
+        if self.emoji.trim().is_empty() || self.emoji.len() > MAX_EMOJI_LENGTH {
+            return Err(Problem::unprocessable(
+                "invalid-emoji",
+                format!("emoji must be between 1 and {MAX_EMOJI_LENGTH} bytes"),
+            ));
+        }
+
+        Ok(Outgoing {
+            reaction: Some(ReactionTarget {
+                emoji: self.emoji,
+                remove: self.remove,
+                author: parse_author(&self.target_author)?,
+                timestamp: self.target_timestamp,
+            }),
+            ..Default::default()
+        })
+    }
+}
+
+/// React to a message in a thread, or take a reaction back.
+#[utoipa::path(
+    post,
+    path = "/threads/{thread}/reactions",
+    request_body = Reaction,
+    responses(
+        (status = 202, description = "Reaction stored and queued for delivery", body = Accepted),
+        (status = 400, description = "Malformed thread or request body", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid emoji or target author", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Reaction could not be stored", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("thread" = String, Path, description = "The contact UUID or E.164 number, or the group master key or identifier")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn react(
+    Path(destination): Path<String>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+    reaction: Result<Json<Reaction>, JsonRejection>,
+) -> Result<(StatusCode, Json<Accepted>), Problem> {
+    let destination = thread(&destination)?;
+    let Json(reaction) = reaction?;
+    let message = reaction.validate()?;
+
+    enqueue(&session, &outbox, destination, message).await
+}
+
+/// The disappearing-messages setting of a thread.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct Expiration {
+    /// Seconds after which messages disappear once read, `0` to turn the timer off.
+    seconds: u32,
+}
+
+/// Change the disappearing-messages timer of a conversation with a contact.
+///
+/// Messages sent to the thread afterwards carry the new timer. The timer of a group is
+/// part of the group state, which only a group change can update, so groups are turned
+/// away.
+#[utoipa::path(
+    put,
+    path = "/threads/{thread}/expiration",
+    request_body = Expiration,
+    responses(
+        (status = 202, description = "Timer update stored and queued for delivery", body = Accepted),
+        (status = 400, description = "Malformed thread or request body", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Thread is a group", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Timer update could not be stored", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("thread" = String, Path, description = "The contact UUID or E.164 number, or the group master key or identifier")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn set_expiration(
+    Path(destination): Path<String>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+    expiration: Result<Json<Expiration>, JsonRejection>,
+) -> Result<(StatusCode, Json<Accepted>), Problem> {
+    let destination = thread(&destination)?;
+    if let Destination::Group(_) = destination {
+        return Err(Problem::unprocessable(
+            "group-expiration",
+            "the disappearing-messages timer of a group can only be changed by a group update",
+        ));
+    }
+    let Json(expiration) = expiration?;
+
+    let message = Outgoing {
+        expire_timer: Some(expiration.seconds),
+        expiration_update: true,
+        ..Default::default()
+    };
+    enqueue(&session, &outbox, destination, message).await
+}
+
+/// Delete a message sent earlier, for everyone in the thread.
+#[utoipa::path(
+    delete,
+    path = "/threads/{thread}/messages/{timestamp}",
+    responses(
+        (status = 202, description = "Deletion stored and queued for delivery", body = Accepted),
+        (status = 400, description = "Malformed thread or timestamp", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Deletion could not be stored", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("thread" = String, Path, description = "The contact UUID or E.164 number, or the group master key or identifier"),
+        ("timestamp" = u64, Path, description = "The timestamp the message was sent with, as returned by `GET /messages/{id}`")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn delete_message(
+    path: Result<Path<(String, u64)>, PathRejection>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+) -> Result<(StatusCode, Json<Accepted>), Problem> {
+    let Path((destination, timestamp)) = path?;
+    let destination = thread(&destination)?;
+
+    let message = Outgoing {
+        delete: Some(timestamp),
+        ..Default::default()
+    };
+    enqueue(&session, &outbox, destination, message).await
+}
+
+/// Replace the content of a message sent earlier.
+///
+/// Editing an edit replaces the original message again, so either timestamp can be used.
+#[utoipa::path(
+    patch,
+    path = "/threads/{thread}/messages/{timestamp}",
+    request_body = Message,
+    responses(
+        (status = 202, description = "Edit stored and queued for delivery", body = Accepted),
+        (status = 400, description = "Malformed thread, timestamp or request body", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Message content is empty or too long", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Edit could not be stored", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("thread" = String, Path, description = "The contact UUID or E.164 number, or the group master key or identifier"),
+        ("timestamp" = u64, Path, description = "The timestamp the message, or an edit of it, was sent with, as returned by `GET /messages/{id}`")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn edit_message(
+    path: Result<Path<(String, u64)>, PathRejection>,
+    State(session): State<Queue>,
+    State(outbox): State<Outbox>,
+    message: Result<Json<Message>, JsonRejection>,
+) -> Result<(StatusCode, Json<Accepted>), Problem> {
+    let Path((destination, timestamp)) = path?;
+    let destination = thread(&destination)?;
+    let Json(message) = message?;
+
+    let message = Outgoing {
+        edit: Some(timestamp),
+        ..message.validate()?
+    };
+    enqueue(&session, &outbox, destination, message).await
+}
+
+/// How long to keep a typing indicator shown.
+#[derive(Deserialize)]
+pub struct Typing {
+    /// Re-send the indicator until stopped or this many seconds pass.
+    refresh: Option<u64>,
+}
+
+/// Show the typing indicator in a thread.
+///
+/// Without `refresh` clients hide the indicator again after about 15 seconds.
+#[utoipa::path(
+    put,
+    path = "/threads/{thread}/typing",
+    responses(
+        (status = 204, description = "Typing indicator sent"),
+        (status = 400, description = "Malformed thread or refresh", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown contact or group", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Refresh is too long", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full, or Signal rate limited the request", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Signal could not be reached", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Signal service is not running", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("thread" = String, Path, description = "The contact UUID or E.164 number, or the group master key or identifier"),
+        ("refresh" = Option<u64>, Query, description = "Keep the indicator shown for this many seconds, until stopped")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn start_typing(
+    Path(destination): Path<String>,
+    State(session): State<Queue>,
+    typing: Result<Query<Typing>, QueryRejection>,
+) -> Result<StatusCode, Problem> {
+    let destination = thread(&destination)?;
+    let Query(typing) = typing?;
+
+    let refresh_for = match typing.refresh {
+        Some(seconds) if seconds > MAX_TYPING_REFRESH => {
+            return Err(Problem::unprocessable(
+                "invalid-refresh",
+                format!("refresh must be at most {MAX_TYPING_REFRESH} seconds"),
+            ))
+        }
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None => None,
+    };
+
+    dispatch(&session, Command::typing(destination, true, refresh_for)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Hide the typing indicator in a thread, and stop refreshing it.
+#[utoipa::path(
+    delete,
+    path = "/threads/{thread}/typing",
+    responses(
+        (status = 204, description = "Typing indicator stopped"),
+        (status = 400, description = "Malformed thread", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown contact or group", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full, or Signal rate limited the request", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Signal could not be reached", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Signal service is not running", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("thread" = String, Path, description = "The contact UUID or E.164 number, or the group master key or identifier")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn stop_typing(
+    Path(destination): Path<String>,
+    State(session): State<Queue>,
+) -> Result<StatusCode, Problem> {
+    let destination = thread(&destination)?;
+
+    dispatch(&session, Command::typing(destination, false, None)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Messages in a thread to mark read.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct ReadMessages {
+    /// UUID of the author of the messages, only needed in groups.
+    author: Option<String>,
+    /// The timestamps the messages were sent with.
+    timestamps: Vec<u64>,
+}
+
+/// Send read receipts for messages received in a thread.
+///
+/// Receipts go to the author of the messages, who has to be given for group threads.
+#[utoipa::path(
+    post,
+    path = "/threads/{thread}/read",
+    request_body = ReadMessages,
+    responses(
+        (status = 204, description = "Read receipt sent"),
+        (status = 400, description = "Malformed thread or request body", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown author", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Missing author or invalid timestamps", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Queue is full, or Signal rate limited the request", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Signal could not be reached", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Signal service is not running", body = Problem, content_type = "application/problem+json")
+    ),
+    params(
+        ("thread" = String, Path, description = "The contact UUID or E.164 number, or the group master key or identifier")
+    ),
+    security(
+        (), // <-- make optional authentication
+        ("api_key" = [])
+    )
+)]
+pub async fn mark_read(
+    Path(destination): Path<String>,
+    State(session): State<Queue>,
+    read: Result<Json<ReadMessages>, JsonRejection>,
+) -> Result<StatusCode, Problem> {
+    let destination = thread(&destination)?;
+    let Json(read) = read?;
+
+    if read.timestamps.is_empty() || read.timestamps.len() > MAX_READ_TIMESTAMPS {
+        return Err(Problem::unprocessable(
+            "invalid-timestamps",
+            format!("between 1 and {MAX_READ_TIMESTAMPS} timestamps must be given"),
+        ));
+    }
+
+    let author = match (read.author, destination) {
+        (Some(author), _) => Destination::Contact(parse_author(&author)?),
+        (None, Destination::Group(_)) => {
+            return Err(Problem::unprocessable(
+                "missing-author",
+                "the author must be given to mark messages in a group read",
+            ))
+        }
+        (None, contact) => contact,
+    };
+
+    dispatch(&session, Command::mark_read(author, read.timestamps)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}